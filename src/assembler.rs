@@ -0,0 +1,488 @@
+use std::collections::HashMap;
+use std::fmt;
+
+const PROGRAM_START: usize = 0x200;
+const MAX_ADDRESS: i64 = 0xFFF;
+const MAX_WORD: i64 = 0xFFFF;
+const MAX_BYTE: i64 = 0xFF;
+const MAX_NIBBLE: i64 = 0xF;
+const SPRITE_ON: [char; 2] = ['#', 'X'];
+const SPRITE_OFF: [char; 2] = ['.', ' '];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    UnknownMnemonic(String),
+    InvalidOperands(String),
+    UnknownSymbol(String),
+    DuplicateSymbol(String),
+    InvalidNumber(String),
+    OutOfRange { value: i64, max: i64 },
+    InvalidSprite(String),
+}
+
+/// An error in the assembly source, with the 1-based line it was found on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            ErrorKind::UnknownMnemonic(mnemonic) => write!(f, "unknown mnemonic {}", mnemonic),
+            ErrorKind::InvalidOperands(mnemonic) => write!(f, "invalid operands for {}", mnemonic),
+            ErrorKind::UnknownSymbol(symbol) => write!(f, "unknown symbol {}", symbol),
+            ErrorKind::DuplicateSymbol(symbol) => write!(f, "{} is already defined", symbol),
+            ErrorKind::InvalidNumber(number) => write!(f, "invalid number {}", number),
+            ErrorKind::OutOfRange { value, max } => {
+                write!(f, "value {} is out of range, maximum is 0x{:X}", value, max)
+            }
+            ErrorKind::InvalidSprite(sprite) => write!(f, "invalid sprite row {}", sprite),
+        }
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// Assembles source in Cowgod's mnemonics into a ROM loaded at 0x200.
+///
+/// Each line holds an optional `label:`, then an instruction or directive, and
+/// an optional `;` comment. Mnemonics and registers are case insensitive.
+/// Besides instructions the assembler understands:
+///
+/// - `NAME EQU expr` defines a constant from numbers and earlier symbols
+/// - `DB expr, ...` and `DW expr, ...` emit bytes and big endian words
+/// - `DB "#..##..#"` and `DW "################"` emit sprite rows, where `#`
+///   or `X` is a lit pixel and `.` or a space is unlit
+///
+/// Expressions are numbers (decimal, `0x`/`$` hex, `0b`/`%` binary), labels
+/// and constants, joined with `+` and `-`. This is the syntax that
+/// [`disassemble`](crate::disassembler::disassemble) produces for
+/// [`Syntax::Cowgod`](crate::instruction::Syntax::Cowgod).
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssembleError> {
+    let lines = source
+        .lines()
+        .enumerate()
+        .map(|(index, text)| parse_line(index + 1, text))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // First pass assigns addresses to labels and evaluates constants
+    let mut symbols = HashMap::new();
+    let mut address = PROGRAM_START;
+    for line in &lines {
+        if let Some(label) = line.label {
+            define(&mut symbols, line.number, label, address as i64)?;
+        }
+        match line.statement {
+            Some(Statement::Constant(name, expr)) => {
+                let value = evaluate(expr, &symbols, line.number)?;
+                define(&mut symbols, line.number, name, value)?;
+            }
+            Some(Statement::Operation(mnemonic, ref operands)) => {
+                address += size(mnemonic, operands);
+            }
+            None => {}
+        }
+    }
+
+    let mut rom = Vec::new();
+    for line in &lines {
+        if let Some(Statement::Operation(mnemonic, ref operands)) = line.statement {
+            let assembler = Assembler {
+                symbols: &symbols,
+                line: line.number,
+            };
+            assembler.operation(mnemonic, operands, &mut rom)?;
+        }
+    }
+    Ok(rom)
+}
+
+struct Line<'a> {
+    number: usize,
+    label: Option<&'a str>,
+    statement: Option<Statement<'a>>,
+}
+
+enum Statement<'a> {
+    Constant(&'a str, &'a str),
+    Operation(&'a str, Vec<&'a str>),
+}
+
+fn parse_line(number: usize, text: &str) -> Result<Line<'_>, AssembleError> {
+    let mut text = strip_comment(text).trim();
+    let mut label = None;
+    if let Some((name, rest)) = text.split_once(':') {
+        if is_identifier(name.trim()) {
+            label = Some(name.trim());
+            text = rest.trim();
+        }
+    }
+    if text.is_empty() {
+        return Ok(Line {
+            number,
+            label,
+            statement: None,
+        });
+    }
+
+    let (mnemonic, rest) = match text.split_once(char::is_whitespace) {
+        Some((mnemonic, rest)) => (mnemonic, rest.trim()),
+        None => (text, ""),
+    };
+    if let Some((keyword, expr)) = rest.split_once(char::is_whitespace) {
+        if keyword.eq_ignore_ascii_case("EQU") {
+            if !is_identifier(mnemonic) {
+                return Err(AssembleError {
+                    line: number,
+                    kind: ErrorKind::InvalidOperands("EQU".to_string()),
+                });
+            }
+            return Ok(Line {
+                number,
+                label,
+                statement: Some(Statement::Constant(mnemonic, expr.trim())),
+            });
+        }
+    }
+    Ok(Line {
+        number,
+        label,
+        statement: Some(Statement::Operation(mnemonic, split_operands(rest))),
+    })
+}
+
+/// Removes a `;` comment, ignoring any inside a quoted sprite row.
+fn strip_comment(text: &str) -> &str {
+    let mut quoted = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &text[..index],
+            _ => {}
+        }
+    }
+    text
+}
+
+fn split_operands(text: &str) -> Vec<&str> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    let mut operands = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                operands.push(text[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    operands.push(text[start..].trim());
+    operands
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn define<'a>(
+    symbols: &mut HashMap<&'a str, i64>,
+    line: usize,
+    name: &'a str,
+    value: i64,
+) -> Result<(), AssembleError> {
+    match symbols.insert(name, value) {
+        Some(_) => Err(AssembleError {
+            line,
+            kind: ErrorKind::DuplicateSymbol(name.to_string()),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Number of bytes an operation assembles to, known without resolving symbols.
+fn size(mnemonic: &str, operands: &[&str]) -> usize {
+    let long = operands
+        .iter()
+        .any(|operand| long_operand(operand).is_some());
+    match mnemonic.to_ascii_uppercase().as_str() {
+        "DB" => operands.len(),
+        "DW" => operands.len() * 2,
+        "LD" if long => 4,
+        _ => 2,
+    }
+}
+
+fn long_operand(operand: &str) -> Option<&str> {
+    let (keyword, expr) = operand.split_once(char::is_whitespace)?;
+    match keyword.eq_ignore_ascii_case("LONG") {
+        true => Some(expr.trim()),
+        false => None,
+    }
+}
+
+fn evaluate(expr: &str, symbols: &HashMap<&str, i64>, line: usize) -> Result<i64, AssembleError> {
+    let mut value = 0;
+    let mut negate = false;
+    let mut start = 0;
+    let expr = expr.trim();
+    for (index, c) in expr
+        .char_indices()
+        .chain(std::iter::once((expr.len(), '+')))
+    {
+        if index == expr.len() && index == start {
+            return Err(AssembleError {
+                line,
+                kind: ErrorKind::InvalidNumber(expr.to_string()),
+            });
+        }
+        if (c == '+' || c == '-') && index > start {
+            let term = term(expr[start..index].trim(), symbols, line)?;
+            value += if negate { -term } else { term };
+            negate = c == '-';
+            start = index + 1;
+        } else if c == '-' && index == start {
+            negate = !negate;
+            start = index + 1;
+        }
+    }
+    Ok(value)
+}
+
+fn term(text: &str, symbols: &HashMap<&str, i64>, line: usize) -> Result<i64, AssembleError> {
+    let invalid = || AssembleError {
+        line,
+        kind: ErrorKind::InvalidNumber(text.to_string()),
+    };
+    let lower = text.to_ascii_lowercase();
+    let (digits, radix) = if let Some(digits) = lower.strip_prefix("0x") {
+        (digits, 16)
+    } else if let Some(digits) = lower.strip_prefix('$') {
+        (digits, 16)
+    } else if let Some(digits) = lower.strip_prefix("0b") {
+        (digits, 2)
+    } else if let Some(digits) = lower.strip_prefix('%') {
+        (digits, 2)
+    } else if lower.starts_with(|c: char| c.is_ascii_digit()) {
+        (lower.as_str(), 10)
+    } else if is_identifier(text) {
+        return symbols.get(text).copied().ok_or_else(|| AssembleError {
+            line,
+            kind: ErrorKind::UnknownSymbol(text.to_string()),
+        });
+    } else {
+        return Err(invalid());
+    };
+    i64::from_str_radix(digits, radix).map_err(|_| invalid())
+}
+
+enum Operand {
+    V(u16),
+    I,
+    IndirectI,
+    Delay,
+    Sound,
+    Key,
+    Font,
+    BigFont,
+    Bcd,
+    Flags,
+    Long(String),
+    Value(String),
+}
+
+fn parse_operand(text: &str) -> Operand {
+    if let Some(expr) = long_operand(text) {
+        return Operand::Long(expr.to_string());
+    }
+    match text.to_ascii_uppercase().as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::Delay,
+        "ST" => Operand::Sound,
+        "K" => Operand::Key,
+        "F" => Operand::Font,
+        "HF" => Operand::BigFont,
+        "B" => Operand::Bcd,
+        "R" => Operand::Flags,
+        upper => match upper
+            .strip_prefix('V')
+            .filter(|digit| digit.len() == 1)
+            .and_then(|digit| u16::from_str_radix(digit, 16).ok())
+        {
+            Some(register) => Operand::V(register),
+            None => Operand::Value(text.to_string()),
+        },
+    }
+}
+
+struct Assembler<'a> {
+    symbols: &'a HashMap<&'a str, i64>,
+    line: usize,
+}
+
+impl Assembler<'_> {
+    fn error(&self, kind: ErrorKind) -> AssembleError {
+        AssembleError {
+            line: self.line,
+            kind,
+        }
+    }
+
+    fn value(&self, expr: &str, max: i64) -> Result<u16, AssembleError> {
+        let value = evaluate(expr, self.symbols, self.line)?;
+        match (0..=max).contains(&value) {
+            true => Ok(value as u16),
+            false => Err(self.error(ErrorKind::OutOfRange { value, max })),
+        }
+    }
+
+    fn sprite(&self, text: &str, width: usize) -> Result<Option<u16>, AssembleError> {
+        let row = match text.strip_prefix('"').and_then(|row| row.strip_suffix('"')) {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        if row.chars().count() > width {
+            return Err(self.error(ErrorKind::InvalidSprite(text.to_string())));
+        }
+        let mut bits = 0;
+        for (index, pixel) in row.chars().enumerate() {
+            match (SPRITE_ON.contains(&pixel), SPRITE_OFF.contains(&pixel)) {
+                (true, _) => bits |= 1 << (width - 1 - index),
+                (_, true) => {}
+                _ => return Err(self.error(ErrorKind::InvalidSprite(text.to_string()))),
+            }
+        }
+        Ok(Some(bits))
+    }
+
+    fn operation(
+        &self,
+        mnemonic: &str,
+        operands: &[&str],
+        rom: &mut Vec<u8>,
+    ) -> Result<(), AssembleError> {
+        let upper = mnemonic.to_ascii_uppercase();
+        match upper.as_str() {
+            "DB" => {
+                for operand in operands {
+                    let byte = match self.sprite(operand, 8)? {
+                        Some(byte) => byte,
+                        None => self.value(operand, MAX_BYTE)?,
+                    };
+                    rom.push(byte as u8);
+                }
+            }
+            "DW" => {
+                for operand in operands {
+                    let word = match self.sprite(operand, 16)? {
+                        Some(word) => word,
+                        None => self.value(operand, MAX_WORD)?,
+                    };
+                    rom.extend_from_slice(&word.to_be_bytes());
+                }
+            }
+            _ => {
+                let operands = operands
+                    .iter()
+                    .map(|operand| parse_operand(operand))
+                    .collect::<Vec<_>>();
+                let (opcode, long) = self.encode(&upper, &operands)?;
+                rom.extend_from_slice(&opcode.to_be_bytes());
+                if let Some(address) = long {
+                    rom.extend_from_slice(&address.to_be_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Encodes an instruction, returning its opcode and the address word that
+    /// follows `LD I, LONG`.
+    fn encode(
+        &self,
+        mnemonic: &str,
+        operands: &[Operand],
+    ) -> Result<(u16, Option<u16>), AssembleError> {
+        use Operand::*;
+
+        let xy = |opcode: u16, x: u16, y: u16| opcode | (x << 8) | (y << 4);
+        let opcode = match (mnemonic, operands) {
+            ("CLS", []) => 0x00E0,
+            ("RET", []) => 0x00EE,
+            ("SCD", [Value(n)]) => 0x00C0 | self.value(n, MAX_NIBBLE)?,
+            ("SCR", []) => 0x00FB,
+            ("SCL", []) => 0x00FC,
+            ("EXIT", []) => 0x00FD,
+            ("LOW", []) => 0x00FE,
+            ("HIGH", []) => 0x00FF,
+            ("JP", [Value(address)]) => 0x1000 | self.value(address, MAX_ADDRESS)?,
+            ("JP", [V(0), Value(address)]) => 0xB000 | self.value(address, MAX_ADDRESS)?,
+            ("CALL", [Value(address)]) => 0x2000 | self.value(address, MAX_ADDRESS)?,
+            ("SE", [V(x), Value(nn)]) => xy(0x3000, *x, 0) | self.value(nn, MAX_BYTE)?,
+            ("SNE", [V(x), Value(nn)]) => xy(0x4000, *x, 0) | self.value(nn, MAX_BYTE)?,
+            ("SE", [V(x), V(y)]) => xy(0x5000, *x, *y),
+            ("SAVE", [V(x), V(y)]) => xy(0x5002, *x, *y),
+            ("LOAD", [V(x), V(y)]) => xy(0x5003, *x, *y),
+            ("LD", [V(x), Value(nn)]) => xy(0x6000, *x, 0) | self.value(nn, MAX_BYTE)?,
+            ("ADD", [V(x), Value(nn)]) => xy(0x7000, *x, 0) | self.value(nn, MAX_BYTE)?,
+            ("LD", [V(x), V(y)]) => xy(0x8000, *x, *y),
+            ("OR", [V(x), V(y)]) => xy(0x8001, *x, *y),
+            ("AND", [V(x), V(y)]) => xy(0x8002, *x, *y),
+            ("XOR", [V(x), V(y)]) => xy(0x8003, *x, *y),
+            ("ADD", [V(x), V(y)]) => xy(0x8004, *x, *y),
+            ("SUB", [V(x), V(y)]) => xy(0x8005, *x, *y),
+            ("SHR", [V(x), V(y)]) => xy(0x8006, *x, *y),
+            ("SHR", [V(x)]) => xy(0x8006, *x, *x),
+            ("SUBN", [V(x), V(y)]) => xy(0x8007, *x, *y),
+            ("SHL", [V(x), V(y)]) => xy(0x800E, *x, *y),
+            ("SHL", [V(x)]) => xy(0x800E, *x, *x),
+            ("SNE", [V(x), V(y)]) => xy(0x9000, *x, *y),
+            ("LD", [I, Value(address)]) => 0xA000 | self.value(address, MAX_ADDRESS)?,
+            ("RND", [V(x), Value(nn)]) => xy(0xC000, *x, 0) | self.value(nn, MAX_BYTE)?,
+            ("DRW", [V(x), V(y), Value(n)]) => xy(0xD000, *x, *y) | self.value(n, MAX_NIBBLE)?,
+            ("SKP", [V(x)]) => xy(0xE09E, *x, 0),
+            ("SKNP", [V(x)]) => xy(0xE0A1, *x, 0),
+            ("LD", [I, Long(address)]) => {
+                return Ok((0xF000, Some(self.value(address, MAX_WORD)?)));
+            }
+            ("PLANE", [Value(n)]) => xy(0xF001, self.value(n, MAX_NIBBLE)?, 0),
+            ("AUDIO", []) => 0xF002,
+            ("LD", [V(x), Delay]) => xy(0xF007, *x, 0),
+            ("LD", [V(x), Key]) => xy(0xF00A, *x, 0),
+            ("LD", [Delay, V(x)]) => xy(0xF015, *x, 0),
+            ("LD", [Sound, V(x)]) => xy(0xF018, *x, 0),
+            ("ADD", [I, V(x)]) => xy(0xF01E, *x, 0),
+            ("LD", [Font, V(x)]) => xy(0xF029, *x, 0),
+            ("LD", [BigFont, V(x)]) => xy(0xF030, *x, 0),
+            ("LD", [Bcd, V(x)]) => xy(0xF033, *x, 0),
+            ("PITCH", [V(x)]) => xy(0xF03A, *x, 0),
+            ("LD", [IndirectI, V(x)]) => xy(0xF055, *x, 0),
+            ("LD", [V(x), IndirectI]) => xy(0xF065, *x, 0),
+            ("LD", [Flags, V(x)]) => xy(0xF075, *x, 0),
+            ("LD", [V(x), Flags]) => xy(0xF085, *x, 0),
+            _ => {
+                let known = [
+                    "CLS", "RET", "SCD", "SCR", "SCL", "EXIT", "LOW", "HIGH", "JP", "CALL", "SE",
+                    "SNE", "SAVE", "LOAD", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SHR", "SUBN",
+                    "SHL", "RND", "DRW", "SKP", "SKNP", "PLANE", "AUDIO", "PITCH",
+                ];
+                let kind = match known.contains(&mnemonic) {
+                    true => ErrorKind::InvalidOperands(mnemonic.to_string()),
+                    false => ErrorKind::UnknownMnemonic(mnemonic.to_string()),
+                };
+                return Err(self.error(kind));
+            }
+        };
+        Ok((opcode, None))
+    }
+}
@@ -0,0 +1,221 @@
+use crate::chip8::{Cpu, AUDIO_PATTERN_SIZE, FRAMES_PER_SEC};
+use std::io::{self, Write};
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+pub const DEFAULT_VOLUME: f32 = 0.25;
+/// Time taken to fade in or out when the sound timer starts or stops, short
+/// enough not to be heard but long enough to avoid clicks.
+const FADE_SECS: f32 = 0.002;
+const PATTERN_BITS: f64 = (AUDIO_PATTERN_SIZE * 8) as f64;
+const WAV_HEADER_SIZE: u32 = 36;
+const WAV_FORMAT_SIZE: u32 = 16;
+const WAV_FORMAT_PCM: u16 = 1;
+const WAV_CHANNELS: u16 = 1;
+const WAV_BYTES_PER_SAMPLE: u16 = 2;
+
+/// The waveform played while the sound timer is running.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Tone {
+    /// The CPU's audio pattern buffer at the rate set by its pitch register.
+    /// Until an XO-CHIP program loads its own pattern this is a 500 Hz square
+    /// wave.
+    #[default]
+    Pattern,
+    /// A square wave at the given frequency in Hz, ignoring the pattern buffer.
+    Square(f64),
+}
+
+/// Generates mono PCM samples for the buzzer from the CPU's sound state.
+///
+/// Hosts call [`AudioSource::update`] once per frame and pull samples from
+/// the source as their audio device needs them, or render whole frames with
+/// [`AudioSource::render_frame`].
+#[derive(Debug, Clone)]
+pub struct AudioSource {
+    sample_rate: u32,
+    volume: f32,
+    tone: Tone,
+
+    pattern: [u8; AUDIO_PATTERN_SIZE],
+    playback_rate: f64,
+    playing: bool,
+
+    /// Position in the pattern, in bits, or in the square wave's period.
+    phase: f64,
+    gain: f32,
+    /// Fraction of a sample carried over between rendered frames.
+    frame_remainder: f64,
+}
+
+impl AudioSource {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            volume: DEFAULT_VOLUME,
+            tone: Tone::default(),
+
+            pattern: [0; AUDIO_PATTERN_SIZE],
+            playback_rate: 0.,
+            playing: false,
+
+            phase: 0.,
+            gain: 0.,
+            frame_remainder: 0.,
+        }
+    }
+
+    /// Sets the peak amplitude, from 0 for silence to 1 for full scale.
+    pub fn with_volume(mut self, volume: f32) -> Self {
+        self.volume = volume.clamp(0., 1.);
+        self
+    }
+
+    pub fn with_tone(mut self, tone: Tone) -> Self {
+        self.tone = tone;
+        self
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Takes the sound timer, pattern buffer and pitch from the CPU.
+    pub fn update(&mut self, cpu: &Cpu) {
+        self.pattern = cpu.audio_pattern();
+        self.playback_rate = cpu.audio_playback_rate();
+        self.playing = cpu.beep();
+    }
+
+    /// Appends one frame's worth of samples.
+    pub fn render_frame(&mut self, samples: &mut Vec<f32>) {
+        let count = self.sample_rate as f64 / FRAMES_PER_SEC as f64 + self.frame_remainder;
+        self.frame_remainder = count.fract();
+        samples.extend(self.take(count as usize));
+    }
+
+    fn wave(&mut self) -> f32 {
+        let (high, step, period) = match self.tone {
+            Tone::Pattern => {
+                let bit = self.phase as usize;
+                let high = (self.pattern[bit / 8] >> (7 - (bit % 8))) & 1 == 1;
+                (high, self.playback_rate, PATTERN_BITS)
+            }
+            Tone::Square(frequency) => (self.phase < 0.5, frequency, 1.),
+        };
+        self.phase = (self.phase + step / self.sample_rate as f64) % period;
+        match high {
+            true => 1.,
+            false => -1.,
+        }
+    }
+}
+
+impl Iterator for AudioSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let fade_step = 1. / (FADE_SECS * self.sample_rate as f32);
+        self.gain = match self.playing {
+            true => (self.gain + fade_step).min(1.),
+            false => (self.gain - fade_step).max(0.),
+        };
+        Some(match self.gain > 0. {
+            true => self.wave() * self.gain * self.volume,
+            false => 0.,
+        })
+    }
+}
+
+/// Writes samples as a mono 16 bit PCM WAV file.
+pub fn write_wav(mut writer: impl Write, sample_rate: u32, samples: &[f32]) -> io::Result<()> {
+    let data_size = samples.len() as u32 * WAV_BYTES_PER_SAMPLE as u32;
+    let block_align = WAV_CHANNELS * WAV_BYTES_PER_SAMPLE;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(WAV_HEADER_SIZE + data_size).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&WAV_FORMAT_SIZE.to_le_bytes())?;
+    writer.write_all(&WAV_FORMAT_PCM.to_le_bytes())?;
+    writer.write_all(&WAV_CHANNELS.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&(WAV_BYTES_PER_SAMPLE * 8).to_le_bytes())?;
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        let sample = (sample.clamp(-1., 1.) * i16::MAX as f32) as i16;
+        writer.write_all(&sample.to_le_bytes())?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Quirks;
+
+    /// A program that sets the sound timer to 2 frames, then loops.
+    fn beeping_cpu() -> Cpu {
+        // 0x200: LD V0, 2; 0x202: LD ST, V0; 0x204: JP 0x204
+        let rom = [0x60, 0x02, 0xF0, 0x18, 0x12, 0x04];
+        let mut cpu = Cpu::from_bytes(&rom, Quirks::default()).unwrap();
+        cpu.cycle().unwrap();
+        cpu.cycle().unwrap();
+        cpu
+    }
+
+    /// Counts periods, which start high.
+    fn falling_edges(samples: &[f32]) -> usize {
+        samples
+            .windows(2)
+            .filter(|w| w[0] > 0. && w[1] <= 0.)
+            .count()
+    }
+
+    #[test]
+    fn square_tone_has_its_frequency_and_volume() {
+        let mut source = AudioSource::new(48000)
+            .with_tone(Tone::Square(1000.))
+            .with_volume(0.5);
+        source.update(&beeping_cpu());
+        let samples: Vec<_> = source.by_ref().take(4800).collect();
+        assert_eq!(falling_edges(&samples), 100);
+        let peak = samples.iter().fold(0f32, |peak, sample| peak.max(*sample));
+        assert_eq!(peak, 0.5);
+    }
+
+    #[test]
+    fn default_pattern_is_a_500_hz_square_that_stops_with_the_timer() {
+        let mut cpu = beeping_cpu();
+        let mut source = AudioSource::new(48000);
+        let mut samples = Vec::new();
+        for _ in 0..3 {
+            source.update(&cpu);
+            source.render_frame(&mut samples);
+            cpu.tick_timers();
+        }
+        assert_eq!(samples.len(), 3 * 800);
+        // 500 Hz over the 2 frames the timer runs for, then silence
+        assert_eq!(falling_edges(&samples[..1600]), 17);
+        assert!(samples[1600 + 200..].iter().all(|sample| *sample == 0.));
+    }
+
+    #[test]
+    fn wav_has_a_pcm_header() {
+        let mut wav = Vec::new();
+        write_wav(&mut wav, 8000, &[0., 1., -1.]).unwrap();
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes([wav[4], wav[5], wav[6], wav[7]]), 36 + 6);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(
+            u32::from_le_bytes([wav[24], wav[25], wav[26], wav[27]]),
+            8000
+        );
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(&wav[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+}
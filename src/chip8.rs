@@ -1,18 +1,45 @@
+use crate::instruction::{decode, Instruction};
+use crate::quirks::Quirks;
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::timing;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::fmt;
 use std::io::Read;
-use std::time::{Duration, Instant};
 
-type Opcode = u16;
+pub type Opcode = u16;
 
 pub const DISPLAY_WIDTH: usize = 64;
 pub const DISPLAY_HEIGHT: usize = 32;
+pub const HIRES_DISPLAY_WIDTH: usize = 128;
+pub const HIRES_DISPLAY_HEIGHT: usize = 64;
+pub const AUDIO_PATTERN_SIZE: usize = 16;
+/// Rate at which hosts run frames and tick the timers.
+pub const FRAMES_PER_SEC: u32 = 60;
 
+const BIG_FONT_START: usize = 0x0A0;
+const BIG_FONT_HEIGHT: usize = 10;
 const FONT_START: usize = 0x050;
 const FONT_HEIGHT: usize = 5;
-const KEY_COUNT: usize = 16;
+pub const KEY_COUNT: usize = 16;
+const KEY_WAIT_IDLE: u8 = 0;
+const KEY_WAIT_PRESS: u8 = 1;
+const KEY_WAIT_RELEASE: u8 = 2;
+const VBLANK_IDLE: u8 = 0;
+const VBLANK_WAITING: u8 = 1;
+const VBLANK_READY: u8 = 2;
 const MEMORY_SIZE: usize = 4096;
+const XO_MEMORY_SIZE: usize = 0x10000;
+const PLANE_COUNT: usize = 2;
+const DEFAULT_PLANES: u8 = 0b01;
+const DEFAULT_PITCH: u8 = 64;
+const DEFAULT_AUDIO_PATTERN: [u8; AUDIO_PATTERN_SIZE] = [0xF0; AUDIO_PATTERN_SIZE];
+const LONG_LOAD_OPCODE: Opcode = 0xF000;
 const PROGRAM_COUNTER_START: u16 = 0x200;
-const TIMER_TICKS_PER_SEC: f64 = 60.;
-const V_COUNT: usize = 16;
+const RPL_FLAG_COUNT: usize = 16;
+const SCROLL_HORIZONTAL_PIXELS: usize = 4;
+const STACK_SIZE: usize = 16;
+pub const V_COUNT: usize = 16;
 const V_CARRY_FLAG: usize = 15;
 
 const FONTS: [u8; 80] = [
@@ -34,9 +61,129 @@ const FONTS: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
+const BIG_FONTS: [u8; 160] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+#[derive(Debug)]
+pub enum LoadError {
+    TooLarge { size: usize, max: usize },
+    Empty,
+    Io(std::io::Error),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::TooLarge { size, max } => write!(
+                f,
+                "rom is {} bytes but at most {} bytes fit in memory",
+                size, max
+            ),
+            LoadError::Empty => write!(f, "rom is empty"),
+            LoadError::Io(err) => write!(f, "failed to read rom: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for LoadError {
+    fn from(err: std::io::Error) -> Self {
+        LoadError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    StackUnderflow { pc: u16 },
+    StackOverflow { pc: u16 },
+    InvalidOpcode { pc: u16, opcode: Opcode },
+    InvalidKey { pc: u16, key: u8 },
+    MemoryOutOfBounds { pc: u16, address: usize },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::StackUnderflow { pc } => {
+                write!(f, "return with an empty stack at 0x{:04X}", pc)
+            }
+            CpuError::StackOverflow { pc } => {
+                write!(f, "call with a full stack at 0x{:04X}", pc)
+            }
+            CpuError::InvalidOpcode { pc, opcode } => {
+                write!(f, "invalid opcode 0x{:04X} at 0x{:04X}", opcode, pc)
+            }
+            CpuError::InvalidKey { pc, key } => {
+                write!(f, "invalid key 0x{:02X} at 0x{:04X}", key, pc)
+            }
+            CpuError::MemoryOutOfBounds { pc, address } => write!(
+                f,
+                "memory access out of bounds at 0x{:X} from 0x{:04X}",
+                address, pc
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A data access made by an instruction. Instruction fetches are not included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub address: usize,
+    pub kind: AccessKind,
+}
+
+/// Progress of an FX0A that waits for a key to be pressed and released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyWait {
+    Idle,
+    Press,
+    Release(u8),
+}
+
+/// Progress of a DXYN that waits for the vertical blank before drawing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Vblank {
+    Idle,
+    Waiting,
+    Ready,
+}
+
 pub struct Cpu {
-    memory: [u8; MEMORY_SIZE],
+    memory: Vec<u8>,
     program_counter: u16,
+    instruction_address: u16,
 
     i: u16,
     v: [u8; V_COUNT],
@@ -45,223 +192,1685 @@ pub struct Cpu {
 
     delay_timer: u8,
     sound_timer: u8,
-    prev_timer_time: Instant,
 
-    display: [bool; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+    audio_pattern: [u8; AUDIO_PATTERN_SIZE],
+    pitch: u8,
+
+    display: [u8; HIRES_DISPLAY_WIDTH * HIRES_DISPLAY_HEIGHT],
     display_modified: bool,
+    display_erased: bool,
+    hires: bool,
+    selected_planes: u8,
+    vblank: Vblank,
 
     pressed_keys: [bool; KEY_COUNT],
+    /// Keys pressed since FX0A started waiting.
+    key_presses: [bool; KEY_COUNT],
+    key_wait: KeyWait,
+
+    rpl_flags: [u8; RPL_FLAG_COUNT],
+    halted: bool,
+
+    trace_memory: bool,
+    memory_accesses: Vec<MemoryAccess>,
+
+    seed: u64,
+    rng: ChaCha8Rng,
+
+    /// Machine cycles left in the frame under VIP timing, negative when the
+    /// last instruction ran over into the next frame.
+    vip_cycles: i64,
+
+    quirks: Quirks,
 }
 
 impl Cpu {
-    pub fn new(mut rom: std::fs::File) -> Self {
+    pub fn from_reader(rom: impl Read, quirks: Quirks) -> Result<Self, LoadError> {
+        let max = Self::max_rom_size(quirks);
+        let mut bytes = Vec::new();
+        // Read one byte past the limit so oversized roms are detected without reading them fully
+        rom.take(max as u64 + 1).read_to_end(&mut bytes)?;
+        Self::from_bytes(&bytes, quirks)
+    }
+
+    pub fn from_bytes(rom: &[u8], quirks: Quirks) -> Result<Self, LoadError> {
+        let max = Self::max_rom_size(quirks);
+        if rom.is_empty() {
+            return Err(LoadError::Empty);
+        }
+        if rom.len() > max {
+            return Err(LoadError::TooLarge {
+                size: rom.len(),
+                max,
+            });
+        }
+        let seed = rand::random();
+
         let mut cpu = Self {
-            memory: [0; MEMORY_SIZE],
+            memory: vec![0; Self::memory_size(quirks)],
             program_counter: PROGRAM_COUNTER_START,
+            instruction_address: PROGRAM_COUNTER_START,
 
             i: 0,
             v: [0; V_COUNT],
 
-            stack: Vec::with_capacity(16),
+            stack: Vec::with_capacity(STACK_SIZE),
 
             delay_timer: 0,
             sound_timer: 0,
-            prev_timer_time: Instant::now(),
 
-            display: [false; DISPLAY_WIDTH * DISPLAY_HEIGHT],
-            display_modified: false,
+            audio_pattern: DEFAULT_AUDIO_PATTERN,
+            pitch: DEFAULT_PITCH,
+
+            display: [0; HIRES_DISPLAY_WIDTH * HIRES_DISPLAY_HEIGHT],
+            // Hosts should present the blank display before anything is drawn
+            display_modified: true,
+            display_erased: false,
+            hires: false,
+            selected_planes: DEFAULT_PLANES,
+            vblank: Vblank::Idle,
 
             pressed_keys: [false; KEY_COUNT],
+            key_presses: [false; KEY_COUNT],
+            key_wait: KeyWait::Idle,
+
+            rpl_flags: [0; RPL_FLAG_COUNT],
+            halted: false,
+
+            trace_memory: false,
+            memory_accesses: Vec::new(),
+
+            seed,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+
+            vip_cycles: 0,
+
+            quirks,
         };
         (&FONTS[..])
             .read_exact(&mut cpu.memory[FONT_START..(FONT_START + FONTS.len())])
             .unwrap();
-        rom.read_exact(
-            &mut cpu.memory[PROGRAM_COUNTER_START as usize
-                ..(PROGRAM_COUNTER_START as usize + rom.metadata().unwrap().len() as usize)],
-        )
-        .unwrap();
-        cpu
+        (&BIG_FONTS[..])
+            .read_exact(&mut cpu.memory[BIG_FONT_START..(BIG_FONT_START + BIG_FONTS.len())])
+            .unwrap();
+        cpu.memory[PROGRAM_COUNTER_START as usize..(PROGRAM_COUNTER_START as usize + rom.len())]
+            .copy_from_slice(rom);
+        Ok(cpu)
+    }
+
+    /// Reseeds the random number generator used by CXNN so runs can be reproduced.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self.rng = ChaCha8Rng::seed_from_u64(seed);
+        self
+    }
+
+    /// Serializes the full machine state into a versioned snapshot.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+
+        writer.bool(self.quirks.shift_uses_vy);
+        writer.bool(self.quirks.load_store_increments_i);
+        writer.bool(self.quirks.load_store_increments_i_by_x);
+        writer.bool(self.quirks.jump_uses_vx);
+        writer.bool(self.quirks.clip_sprites);
+        writer.bool(self.quirks.vf_reset);
+        writer.bool(self.quirks.i_overflow_sets_vf);
+        writer.bool(self.quirks.extended_memory);
+        writer.bool(self.quirks.wait_key_release);
+        writer.bool(self.quirks.display_wait);
+
+        writer.u32(self.memory.len() as u32);
+        writer.bytes(&self.memory);
+        writer.u16(self.program_counter);
+
+        writer.u16(self.i);
+        writer.bytes(&self.v);
+
+        writer.u8(self.stack.len() as u8);
+        for address in &self.stack {
+            writer.u16(*address);
+        }
+
+        writer.u8(self.delay_timer);
+        writer.u8(self.sound_timer);
+
+        writer.bytes(&self.audio_pattern);
+        writer.u8(self.pitch);
+
+        writer.bytes(&self.display);
+        writer.bool(self.hires);
+        writer.u8(self.selected_planes);
+        writer.u8(match self.vblank {
+            Vblank::Idle => VBLANK_IDLE,
+            Vblank::Waiting => VBLANK_WAITING,
+            Vblank::Ready => VBLANK_READY,
+        });
+
+        for key in &self.pressed_keys {
+            writer.bool(*key);
+        }
+        for key in &self.key_presses {
+            writer.bool(*key);
+        }
+        match self.key_wait {
+            KeyWait::Idle => writer.u8(KEY_WAIT_IDLE),
+            KeyWait::Press => writer.u8(KEY_WAIT_PRESS),
+            KeyWait::Release(key) => {
+                writer.u8(KEY_WAIT_RELEASE);
+                writer.u8(key);
+            }
+        }
+
+        writer.bytes(&self.rpl_flags);
+        writer.bool(self.halted);
+
+        writer.u64(self.seed);
+        writer.u128(self.rng.get_word_pos());
+
+        writer.u64(self.vip_cycles as u64);
+
+        writer.finish()
+    }
+
+    /// Restores a snapshot taken by [`Cpu::save_state`]. The current state is left
+    /// untouched if the snapshot can't be read.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(state)?;
+
+        let quirks = Quirks {
+            shift_uses_vy: reader.bool()?,
+            load_store_increments_i: reader.bool()?,
+            load_store_increments_i_by_x: reader.bool()?,
+            jump_uses_vx: reader.bool()?,
+            clip_sprites: reader.bool()?,
+            vf_reset: reader.bool()?,
+            i_overflow_sets_vf: reader.bool()?,
+            extended_memory: reader.bool()?,
+            wait_key_release: reader.bool()?,
+            display_wait: reader.bool()?,
+        };
+
+        let memory_size = reader.u32()? as usize;
+        if memory_size != Self::memory_size(quirks) {
+            return Err(StateError::Invalid("memory size does not match quirks"));
+        }
+        let memory = reader.bytes(memory_size)?.to_vec();
+        let program_counter = reader.u16()?;
+
+        let i = reader.u16()?;
+        let v = reader.array()?;
+
+        let stack_len = reader.u8()? as usize;
+        if stack_len > STACK_SIZE {
+            return Err(StateError::Invalid("stack is too deep"));
+        }
+        let mut stack = Vec::with_capacity(STACK_SIZE);
+        for _ in 0..stack_len {
+            stack.push(reader.u16()?);
+        }
+
+        let delay_timer = reader.u8()?;
+        let sound_timer = reader.u8()?;
+
+        let audio_pattern = reader.array()?;
+        let pitch = reader.u8()?;
+
+        let display = reader.array()?;
+        let hires = reader.bool()?;
+        let selected_planes = reader.u8()?;
+        let vblank = match reader.u8()? {
+            VBLANK_IDLE => Vblank::Idle,
+            VBLANK_WAITING => Vblank::Waiting,
+            VBLANK_READY => Vblank::Ready,
+            _ => return Err(StateError::Invalid("unknown vblank state")),
+        };
+
+        let mut pressed_keys = [false; KEY_COUNT];
+        for key in pressed_keys.iter_mut() {
+            *key = reader.bool()?;
+        }
+        let mut key_presses = [false; KEY_COUNT];
+        for key in key_presses.iter_mut() {
+            *key = reader.bool()?;
+        }
+        let key_wait = match reader.u8()? {
+            KEY_WAIT_IDLE => KeyWait::Idle,
+            KEY_WAIT_PRESS => KeyWait::Press,
+            KEY_WAIT_RELEASE => match reader.u8()? {
+                key if (key as usize) < KEY_COUNT => KeyWait::Release(key),
+                _ => return Err(StateError::Invalid("key wait is for an unknown key")),
+            },
+            _ => return Err(StateError::Invalid("unknown key wait state")),
+        };
+
+        let rpl_flags = reader.array()?;
+        let halted = reader.bool()?;
+
+        let seed = reader.u64()?;
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        rng.set_word_pos(reader.u128()?);
+
+        let vip_cycles = reader.u64()? as i64;
+
+        reader.finish()?;
+
+        *self = Self {
+            memory,
+            program_counter,
+            instruction_address: program_counter,
+
+            i,
+            v,
+
+            stack,
+
+            delay_timer,
+            sound_timer,
+
+            audio_pattern,
+            pitch,
+
+            display,
+            display_modified: true,
+            display_erased: false,
+            hires,
+            selected_planes,
+            vblank,
+
+            pressed_keys,
+            key_presses,
+            key_wait,
+
+            rpl_flags,
+            halted,
+
+            trace_memory: self.trace_memory,
+            memory_accesses: Vec::new(),
+
+            seed,
+            rng,
+
+            vip_cycles,
+
+            quirks,
+        };
+        Ok(())
+    }
+
+    pub fn max_rom_size(quirks: Quirks) -> usize {
+        Self::memory_size(quirks) - PROGRAM_COUNTER_START as usize
+    }
+
+    fn memory_size(quirks: Quirks) -> usize {
+        match quirks.extended_memory {
+            true => XO_MEMORY_SIZE,
+            false => MEMORY_SIZE,
+        }
+    }
+
+    /// Each pixel is a bitmask of the bit planes that are lit at that position.
+    pub fn display(&self) -> &[u8] {
+        &self.display[..self.display_width() * self.display_height()]
+    }
+
+    /// Whether the display has changed since the last call, so hosts can skip
+    /// presenting unchanged frames.
+    pub fn take_display_modified(&mut self) -> bool {
+        std::mem::take(&mut self.display_modified)
+    }
+
+    /// Whether the last change to the display only erased pixels, as 00E0 does
+    /// and as DXYN does when erasing a sprite to redraw it elsewhere. The
+    /// display is then usually part way through being redrawn.
+    pub fn display_erased(&self) -> bool {
+        self.display_erased
+    }
+
+    pub fn display_width(&self) -> usize {
+        match self.hires {
+            true => HIRES_DISPLAY_WIDTH,
+            false => DISPLAY_WIDTH,
+        }
+    }
+
+    pub fn display_height(&self) -> usize {
+        match self.hires {
+            true => HIRES_DISPLAY_HEIGHT,
+            false => DISPLAY_HEIGHT,
+        }
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn program_counter(&self) -> u16 {
+        self.program_counter
+    }
+
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn v(&self) -> [u8; V_COUNT] {
+        self.v
+    }
+
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
     }
 
-    pub fn display(&self) -> [bool; DISPLAY_WIDTH * DISPLAY_HEIGHT] {
-        self.display
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
     }
 
     pub fn beep(&self) -> bool {
         self.sound_timer > 0
     }
 
+    pub fn audio_pattern(&self) -> [u8; AUDIO_PATTERN_SIZE] {
+        self.audio_pattern
+    }
+
+    /// Rate in bits per second at which the audio pattern should be played back.
+    pub fn audio_playback_rate(&self) -> f64 {
+        4000. * 2f64.powf((self.pitch as f64 - 64.) / 48.)
+    }
+
+    /// Replaces the held keys. Keys that weren't held before count as pressed
+    /// for an FX0A waiting on a key press.
     pub fn set_keys(&mut self, keys: Vec<usize>) {
+        let previous_keys = self.pressed_keys;
         self.pressed_keys = [false; KEY_COUNT];
         for key in keys {
             if key < KEY_COUNT {
                 self.pressed_keys[key] = true;
+                self.key_presses[key] |= !previous_keys[key];
+            }
+        }
+    }
+
+    /// Runs one frame's worth of instructions followed by a single timer tick.
+    /// Hosts should call this 60 times per second. Stops at the first fault.
+    /// The frame ends early if DXYN starts waiting for the vertical blank.
+    pub fn run_frame(&mut self, cycles_per_frame: u32) -> Result<(), CpuError> {
+        for _ in 0..cycles_per_frame {
+            self.cycle()?;
+            if self.waiting_for_vblank() {
+                break;
+            }
+        }
+        self.tick_timers();
+        Ok(())
+    }
+
+    /// Runs one frame at roughly the speed of a COSMAC VIP, charging each
+    /// instruction an estimate of the machine cycles it took on the original
+    /// interpreter, followed by a single timer tick. Cycles an instruction runs over by are taken from
+    /// the next frame. Hosts should call this 60 times per second.
+    pub fn run_vip_frame(&mut self) -> Result<(), CpuError> {
+        self.vip_cycles += timing::VIP_INTERPRETER_CYCLES_PER_FRAME as i64;
+        while self.vip_cycles > 0 && !self.halted {
+            let pc = self.program_counter;
+            // An opcode that can't be fetched faults in cycle() instead
+            let opcode = self.peek_word(pc as usize).unwrap_or_default();
+            let vx = self.v[((opcode & 0x0F00) >> 8) as usize];
+            self.cycle()?;
+            let instruction = decode(opcode);
+            let skipped = instruction.is_skip()
+                && self.program_counter != pc.wrapping_add(instruction.size() as u16);
+            self.vip_cycles -= timing::vip_cycles(instruction, vx, skipped) as i64;
+            // The rest of the frame passes waiting for the interrupt
+            if self.waiting_for_vblank() {
+                self.vip_cycles = self.vip_cycles.min(0);
+                break;
             }
         }
+        self.tick_timers();
+        Ok(())
     }
 
-    pub fn cycle(&mut self) {
-        if self.prev_timer_time.elapsed() >= Duration::from_secs_f64(1. / TIMER_TICKS_PER_SEC) {
-            self.prev_timer_time = Instant::now();
-            self.process_timers();
+    /// Executes a single instruction. On a fault the program counter is left
+    /// pointing at the faulting instruction.
+    pub fn cycle(&mut self) -> Result<(), CpuError> {
+        if self.halted {
+            return Ok(());
         }
 
-        let opcode = self.fetch();
-        self.process_opcode(opcode);
+        self.instruction_address = self.program_counter;
+        let result = self.fetch().and_then(|opcode| self.process_opcode(opcode));
+        if result.is_err() {
+            self.program_counter = self.instruction_address;
+        }
+        result
     }
 
-    fn process_timers(&mut self) {
+    /// Decrements the delay and sound timers and signals the vertical blank.
+    /// Hosts should call this at 60 Hz, between frames.
+    pub fn tick_timers(&mut self) {
         self.delay_timer = self.delay_timer.saturating_sub(1);
         self.sound_timer = self.sound_timer.saturating_sub(1);
+        if self.vblank == Vblank::Waiting {
+            self.vblank = Vblank::Ready;
+        }
+    }
+
+    /// Whether DXYN is stalled until the next [`Cpu::tick_timers`], so the
+    /// rest of the frame's cycles would be spent waiting.
+    pub fn waiting_for_vblank(&self) -> bool {
+        self.vblank == Vblank::Waiting
+    }
+
+    /// Records the data memory accesses made by each instruction so they can be
+    /// collected with [`Cpu::take_memory_accesses`].
+    pub fn set_memory_tracing(&mut self, enabled: bool) {
+        self.trace_memory = enabled;
+        self.memory_accesses.clear();
+    }
+
+    pub fn take_memory_accesses(&mut self) -> Vec<MemoryAccess> {
+        std::mem::take(&mut self.memory_accesses)
+    }
+
+    fn fetch(&mut self) -> Result<Opcode, CpuError> {
+        let opcode = self.peek_word(self.program_counter as usize)?;
+        self.program_counter = self.program_counter.wrapping_add(2);
+        Ok(opcode)
+    }
+
+    fn skip(&mut self) {
+        // XO-CHIP's F000 NNNN is four bytes long, so skipping it has to step over
+        // the address too. Past the end of memory the next fetch faults instead.
+        let next = match self.quirks.extended_memory {
+            true => self.peek_word(self.program_counter as usize).ok(),
+            false => None,
+        };
+        self.program_counter = self.program_counter.wrapping_add(match next {
+            Some(LONG_LOAD_OPCODE) => 4,
+            _ => 2,
+        });
+    }
+
+    fn increment_i_after_load_store(&mut self, x: usize) {
+        if self.quirks.load_store_increments_i {
+            let registers = match self.quirks.load_store_increments_i_by_x {
+                true => x,
+                false => x + 1,
+            };
+            self.i = self.i.wrapping_add(registers as u16);
+        }
+    }
+
+    fn peek(&self, address: usize) -> Result<u8, CpuError> {
+        self.memory
+            .get(address)
+            .copied()
+            .ok_or(CpuError::MemoryOutOfBounds {
+                pc: self.instruction_address,
+                address,
+            })
+    }
+
+    fn peek_word(&self, address: usize) -> Result<u16, CpuError> {
+        Ok(((self.peek(address)? as u16) << 8) | self.peek(address + 1)? as u16)
+    }
+
+    fn read(&mut self, address: usize) -> Result<u8, CpuError> {
+        let value = self.peek(address)?;
+        self.trace(address, AccessKind::Read);
+        Ok(value)
+    }
+
+    fn read_word(&mut self, address: usize) -> Result<u16, CpuError> {
+        Ok(((self.read(address)? as u16) << 8) | self.read(address + 1)? as u16)
     }
 
-    fn fetch(&mut self) -> Opcode {
-        let opcode = ((self.memory[self.program_counter as usize] as u16) << 8)
-            | (self.memory[self.program_counter as usize + 1] as u16);
-        self.program_counter += 2;
-        opcode
+    fn write(&mut self, address: usize, value: u8) -> Result<(), CpuError> {
+        let pc = self.instruction_address;
+        let byte = self
+            .memory
+            .get_mut(address)
+            .ok_or(CpuError::MemoryOutOfBounds { pc, address })?;
+        *byte = value;
+        self.trace(address, AccessKind::Write);
+        Ok(())
     }
 
-    fn process_opcode(&mut self, opcode: Opcode) {
-        let op_1 = (opcode & 0xF000) >> 12;
-        let op_2 = (opcode & 0x0F00) >> 8;
-        let op_3 = (opcode & 0x00F0) >> 4;
-        let op_4 = opcode & 0x000F;
+    fn trace(&mut self, address: usize, kind: AccessKind) {
+        if self.trace_memory {
+            self.memory_accesses.push(MemoryAccess { address, kind });
+        }
+    }
+
+    fn key_pressed(&self, key: u8) -> Result<bool, CpuError> {
+        self.pressed_keys
+            .get(key as usize)
+            .copied()
+            .ok_or(CpuError::InvalidKey {
+                pc: self.instruction_address,
+                key,
+            })
+    }
+
+    /// Steps FX0A through waiting for a key press and then its release,
+    /// repeating the instruction until the key is released.
+    fn wait_key_release(&mut self, x: usize, pc: u16) {
+        self.key_wait = match self.key_wait {
+            // Keys held before the wait began don't count, so one press isn't read twice
+            KeyWait::Idle => {
+                self.key_presses = [false; KEY_COUNT];
+                KeyWait::Press
+            }
+            KeyWait::Press => match self.key_presses.iter().position(|x| x == &true) {
+                Some(key) => KeyWait::Release(key as u8),
+                None => KeyWait::Press,
+            },
+            KeyWait::Release(key) => match self.pressed_keys[key as usize] {
+                true => KeyWait::Release(key),
+                false => {
+                    self.v[x] = key;
+                    KeyWait::Idle
+                }
+            },
+        };
+        if self.key_wait != KeyWait::Idle {
+            self.program_counter = pc;
+        }
+    }
 
+    fn process_opcode(&mut self, opcode: Opcode) -> Result<(), CpuError> {
+        let pc = self.instruction_address;
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let vx = self.v[x];
         let y = ((opcode & 0x00F0) >> 4) as usize;
         let vy = self.v[y];
-        let nnn = opcode & 0x0FFF;
-        let nn = (opcode & 0x00FF) as u8;
-        let n = (opcode & 0x000F) as u8;
-
-        match (op_1, op_2, op_3, op_4) {
-            (0, 0, 0xE, 0) => {
-                self.display = [false; DISPLAY_WIDTH * DISPLAY_HEIGHT];
-                self.display_modified = true;
-            }
-            (0, 0, 0xE, 0xE) => self.program_counter = self.stack.pop().unwrap(),
-            (1, _, _, _) => self.program_counter = nnn,
-            (2, _, _, _) => {
+
+        match decode(opcode) {
+            Instruction::ScrollDown(n) => self.scroll(0, n as isize),
+            Instruction::ClearScreen => self.clear_display(),
+            Instruction::Return => {
+                self.program_counter = self.stack.pop().ok_or(CpuError::StackUnderflow { pc })?
+            }
+            Instruction::ScrollRight => self.scroll(SCROLL_HORIZONTAL_PIXELS as isize, 0),
+            Instruction::ScrollLeft => self.scroll(-(SCROLL_HORIZONTAL_PIXELS as isize), 0),
+            Instruction::Exit => {
+                self.halted = true;
+                self.program_counter = pc;
+            }
+            Instruction::LowRes => {
+                self.hires = false;
+                self.clear_display();
+            }
+            Instruction::HighRes => {
+                self.hires = true;
+                self.clear_display();
+            }
+            Instruction::Jump(nnn) => self.program_counter = nnn,
+            Instruction::Call(nnn) => {
+                if self.stack.len() == STACK_SIZE {
+                    return Err(CpuError::StackOverflow { pc });
+                }
                 self.stack.push(self.program_counter);
                 self.program_counter = nnn;
             }
-            (3, _, _, _) => {
+            Instruction::SkipIfEqualImmediate(_, nn) => {
                 if vx == nn {
-                    self.program_counter += 2;
+                    self.skip();
                 }
             }
-            (4, _, _, _) => {
+            Instruction::SkipIfNotEqualImmediate(_, nn) => {
                 if vx != nn {
-                    self.program_counter += 2;
+                    self.skip();
                 }
             }
-            (5, _, _, 0) => {
+            Instruction::SkipIfEqual(..) => {
                 if vx == vy {
-                    self.program_counter += 2;
+                    self.skip();
                 }
             }
-            (6, _, _, _) => self.v[x] = nn,
-            (7, _, _, _) => self.v[x] = vx.wrapping_add(nn),
-            (8, _, _, 0) => self.v[x] = vy,
-            (8, _, _, 1) => self.v[x] = vx | vy,
-            (8, _, _, 2) => self.v[x] = vx & vy,
-            (8, _, _, 3) => self.v[x] = vx ^ vy,
-            (8, _, _, 4) => {
+            Instruction::SaveRange(..) => {
+                for (offset, index) in register_range(x, y).enumerate() {
+                    self.write(self.i as usize + offset, self.v[index])?;
+                }
+            }
+            Instruction::LoadRange(..) => {
+                for (offset, index) in register_range(x, y).enumerate() {
+                    self.v[index] = self.read(self.i as usize + offset)?;
+                }
+            }
+            Instruction::LoadImmediate(_, nn) => self.v[x] = nn,
+            Instruction::AddImmediate(_, nn) => self.v[x] = vx.wrapping_add(nn),
+            Instruction::Move(..) => self.v[x] = vy,
+            Instruction::Or(..) => {
+                self.v[x] = vx | vy;
+                self.reset_carry_flag();
+            }
+            Instruction::And(..) => {
+                self.v[x] = vx & vy;
+                self.reset_carry_flag();
+            }
+            Instruction::Xor(..) => {
+                self.v[x] = vx ^ vy;
+                self.reset_carry_flag();
+            }
+            Instruction::Add(..) => {
                 let (sum, overflow) = vx.overflowing_add(vy);
                 self.v[x] = sum;
                 self.v[V_CARRY_FLAG] = overflow as u8;
             }
-            (8, _, _, 5) => {
+            Instruction::Subtract(..) => {
                 let (sub, overflow) = vx.overflowing_sub(vy);
                 self.v[x] = sub;
                 self.v[V_CARRY_FLAG] = !overflow as u8;
             }
-            (8, _, _, 6) => {
-                self.v[x] = vx >> 1;
-                self.v[V_CARRY_FLAG] = vx & 1;
+            Instruction::ShiftRight(..) => {
+                let value = if self.quirks.shift_uses_vy { vy } else { vx };
+                self.v[x] = value >> 1;
+                self.v[V_CARRY_FLAG] = value & 1;
             }
-            (8, _, _, 7) => {
+            Instruction::SubtractReverse(..) => {
                 let (sub, overflow) = vy.overflowing_sub(vx);
                 self.v[x] = sub;
                 self.v[V_CARRY_FLAG] = !overflow as u8;
             }
-            (8, _, _, 0xE) => {
-                self.v[x] = vx << 1;
-                self.v[V_CARRY_FLAG] = if (vx & 0x80) == 0 { 0 } else { 1 };
+            Instruction::ShiftLeft(..) => {
+                let value = if self.quirks.shift_uses_vy { vy } else { vx };
+                self.v[x] = value << 1;
+                self.v[V_CARRY_FLAG] = if (value & 0x80) == 0 { 0 } else { 1 };
             }
-            (9, _, _, 0) => {
+            Instruction::SkipIfNotEqual(..) => {
                 if vx != vy {
-                    self.program_counter += 2;
+                    self.skip();
+                }
+            }
+            Instruction::LoadI(nnn) => self.i = nnn,
+            Instruction::JumpOffset(nnn) => {
+                let offset = if self.quirks.jump_uses_vx {
+                    vx
+                } else {
+                    self.v[0]
+                };
+                self.program_counter = nnn + offset as u16;
+            }
+            Instruction::Random(_, nn) => self.v[x] = self.rng.gen::<u8>() & nn,
+            Instruction::Draw(_, _, n) => {
+                if self.quirks.display_wait && self.vblank != Vblank::Ready {
+                    self.vblank = Vblank::Waiting;
+                    self.program_counter = pc;
+                    return Ok(());
+                }
+                self.vblank = Vblank::Idle;
+                self.display_opcode(vx, vy, n)?
+            }
+            Instruction::SkipIfKey(_) => {
+                if self.key_pressed(vx)? {
+                    self.skip();
                 }
             }
-            (0xA, _, _, _) => self.i = nnn,
-            (0xB, _, _, _) => self.program_counter = nnn + self.v[0] as u16,
-            (0xC, _, _, _) => self.v[x] = rand::random::<u8>() & nn,
-            (0xD, _, _, _) => self.display_opcode(vx, vy, n),
-            (0xE, _, 9, 0xE) => {
-                if self.pressed_keys[vx as usize] {
-                    self.program_counter += 2;
+            Instruction::SkipIfNotKey(_) => {
+                if !self.key_pressed(vx)? {
+                    self.skip();
                 }
             }
-            (0xE, _, 0xA, 1) => {
-                if !self.pressed_keys[vx as usize] {
-                    self.program_counter += 2;
+            Instruction::LongLoadI => match self.quirks.extended_memory {
+                true => self.i = self.fetch()?,
+                false => return Err(CpuError::InvalidOpcode { pc, opcode }),
+            },
+            Instruction::SelectPlanes(planes) => self.selected_planes = planes & 0b11,
+            Instruction::LoadAudio => {
+                for index in 0..AUDIO_PATTERN_SIZE {
+                    self.audio_pattern[index] = self.read(self.i as usize + index)?;
                 }
             }
-            (0xF, _, 0, 7) => self.v[x] = self.delay_timer,
-            (0xF, _, 0, 0xA) => match self.pressed_keys.iter().position(|x| x == &true) {
-                Some(index) => self.v[x] = index as u8,
-                None => self.program_counter -= 2,
+            Instruction::LoadDelay(_) => self.v[x] = self.delay_timer,
+            Instruction::WaitKey(_) => match self.quirks.wait_key_release {
+                true => self.wait_key_release(x, pc),
+                false => match self.pressed_keys.iter().position(|x| x == &true) {
+                    Some(index) => self.v[x] = index as u8,
+                    None => self.program_counter = pc,
+                },
             },
-            (0xF, _, 1, 5) => self.delay_timer = self.v[x],
-            (0xF, _, 1, 8) => self.sound_timer = self.v[x],
-            (0xF, _, 1, 0xE) => self.i = self.i.wrapping_add(vx as u16),
-            (0xF, _, 2, 9) => {
+            Instruction::SetDelay(_) => self.delay_timer = self.v[x],
+            Instruction::SetSound(_) => self.sound_timer = self.v[x],
+            Instruction::AddI(_) => {
+                self.i = self.i.wrapping_add(vx as u16);
+                if self.quirks.i_overflow_sets_vf {
+                    self.v[V_CARRY_FLAG] = (self.i as usize >= MEMORY_SIZE) as u8;
+                }
+            }
+            Instruction::LoadFont(_) => {
                 self.i = (FONT_START + (FONT_HEIGHT * (vx & 0x0F) as usize)) as u16;
             }
-            (0xF, _, 3, 3) => {
-                self.memory[self.i as usize] = vx / 100 % 10;
-                self.memory[(self.i + 1) as usize] = vx / 10 % 10;
-                self.memory[(self.i + 2) as usize] = vx % 10;
+            Instruction::LoadBigFont(_) => {
+                self.i = (BIG_FONT_START + (BIG_FONT_HEIGHT * (vx & 0x0F) as usize)) as u16;
             }
-            (0xF, _, 5, 5) => {
+            Instruction::SetPitch(_) => self.pitch = vx,
+            Instruction::Bcd(_) => {
+                self.write(self.i as usize, vx / 100 % 10)?;
+                self.write(self.i as usize + 1, vx / 10 % 10)?;
+                self.write(self.i as usize + 2, vx % 10)?;
+            }
+            Instruction::Store(_) => {
                 for index in 0..=x {
-                    self.memory[self.i as usize + index] = self.v[index];
+                    self.write(self.i as usize + index, self.v[index])?;
                 }
+                self.increment_i_after_load_store(x);
             }
-            (0xF, _, 6, 5) => {
+            Instruction::Load(_) => {
                 for index in 0..=x {
-                    self.v[index] = self.memory[self.i as usize + index];
+                    self.v[index] = self.read(self.i as usize + index)?;
                 }
+                self.increment_i_after_load_store(x);
             }
-
-            _ => println!("unsupported opcode 0x{:04X}", opcode),
+            Instruction::StoreFlags(_) => {
+                for index in 0..=x {
+                    self.rpl_flags[index] = self.v[index];
+                }
+            }
+            Instruction::LoadFlags(_) => {
+                for index in 0..=x {
+                    self.v[index] = self.rpl_flags[index];
+                }
+            }
+            Instruction::Unknown(_) => return Err(CpuError::InvalidOpcode { pc, opcode }),
         };
+        Ok(())
+    }
+
+    fn reset_carry_flag(&mut self) {
+        if self.quirks.vf_reset {
+            self.v[V_CARRY_FLAG] = 0;
+        }
+    }
+
+    fn clear_display(&mut self) {
+        for pixel in self.display.iter_mut() {
+            *pixel &= !self.selected_planes;
+        }
+        self.display_modified = true;
+        self.display_erased = true;
     }
 
-    fn display_opcode(&mut self, x: u8, y: u8, height: u8) {
-        let x = x as usize % DISPLAY_WIDTH;
-        let y = y as usize % DISPLAY_HEIGHT;
-        let height = height as usize;
+    fn scroll(&mut self, right: isize, down: isize) {
+        let width = self.display_width();
+        let height = self.display_height();
+        let previous = self.display;
+        for y in 0..height {
+            for x in 0..width {
+                let source_x = x as isize - right;
+                let source_y = y as isize - down;
+                let on_screen = (0..width as isize).contains(&source_x)
+                    && (0..height as isize).contains(&source_y);
+                let scrolled = match on_screen {
+                    true => previous[source_x as usize + (width * source_y as usize)],
+                    false => 0,
+                };
+                let pixel = &mut self.display[x + (width * y)];
+                *pixel = (*pixel & !self.selected_planes) | (scrolled & self.selected_planes);
+            }
+        }
+        self.display_modified = true;
+        self.display_erased = false;
+    }
+
+    fn display_opcode(&mut self, x: u8, y: u8, height: u8) -> Result<(), CpuError> {
+        let width = self.display_width();
+        let display_height = self.display_height();
+        let x = x as usize % width;
+        let y = y as usize % display_height;
+        // DXY0 draws a 16x16 sprite stored as two bytes per row
+        let (sprite_width, height) = match height {
+            0 => (16, 16),
+            _ => (8, height as usize),
+        };
+        let bytes_per_row = sprite_width / 8;
+        let sprite_size = bytes_per_row * height;
 
         self.v[V_CARRY_FLAG] = 0;
-        for row in 0..height {
-            let sprite = self.memory[self.i as usize + row];
-            for col in 0..8 {
-                let bit = (sprite >> (7 - col)) & 1;
-                if (bit == 1) && (x + col < DISPLAY_WIDTH) && (y + row < DISPLAY_HEIGHT) {
-                    let pixel = &mut self.display[(x + col) + (DISPLAY_WIDTH * (y + row))];
-                    if *pixel {
-                        self.v[V_CARRY_FLAG] = 1;
+        let mut toggled = false;
+        let mut lit = false;
+        // Each selected plane consumes its own sprite, stored one after the other from I
+        let selected_planes = self.selected_planes;
+        let planes = (0..PLANE_COUNT)
+            .map(|plane| 1 << plane)
+            .filter(|plane| selected_planes & plane != 0);
+        for (plane_index, plane) in planes.enumerate() {
+            let sprite_start = self.i as usize + (plane_index * sprite_size);
+            for row in 0..height {
+                let address = sprite_start + (row * bytes_per_row);
+                let sprite = match bytes_per_row {
+                    2 => self.read_word(address)?,
+                    _ => (self.read(address)? as u16) << 8,
+                };
+                for col in 0..sprite_width {
+                    let bit = (sprite >> (15 - col)) & 1;
+                    let (pixel_x, pixel_y) = (x + col, y + row);
+                    let clipped = pixel_x >= width || pixel_y >= display_height;
+                    if (bit == 1) && !(clipped && self.quirks.clip_sprites) {
+                        let pixel_x = pixel_x % width;
+                        let pixel_y = pixel_y % display_height;
+                        let pixel = &mut self.display[pixel_x + (width * pixel_y)];
+                        if *pixel & plane != 0 {
+                            self.v[V_CARRY_FLAG] = 1;
+                        }
+                        *pixel ^= plane;
+                        toggled = true;
+                        lit |= *pixel & plane != 0;
                     }
-                    *pixel = !*pixel;
                 }
             }
         }
 
         self.display_modified = true;
+        if toggled {
+            self.display_erased = !lit;
+        }
+        Ok(())
+    }
+}
+
+/// Registers touched by 5XY2/5XY3, in order from X to Y even when X > Y.
+fn register_range(x: usize, y: usize) -> Box<dyn Iterator<Item = usize>> {
+    match x <= y {
+        true => Box::new(x..=y),
+        false => Box::new((y..=x).rev()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{MAGIC, VERSION};
+
+    fn cpu_with(quirks: Quirks, program: &[Opcode]) -> Cpu {
+        let rom = program
+            .iter()
+            .flat_map(|opcode| opcode.to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+        Cpu::from_bytes(&rom, quirks).unwrap().with_seed(0)
+    }
+
+    /// A VIP that draws without waiting, so tests can run one instruction at a time.
+    fn cpu(program: &[Opcode]) -> Cpu {
+        let quirks = Quirks {
+            display_wait: false,
+            ..Quirks::COSMAC_VIP
+        };
+        cpu_with(quirks, program)
+    }
+
+    fn run(cpu: &mut Cpu, cycles: usize) {
+        for _ in 0..cycles {
+            cpu.cycle().unwrap();
+        }
+    }
+
+    fn pixel(cpu: &Cpu, x: usize, y: usize) -> u8 {
+        cpu.display[x + (cpu.display_width() * y)]
+    }
+
+    #[test]
+    fn from_bytes_rejects_empty_and_oversized_roms() {
+        assert!(matches!(
+            Cpu::from_bytes(&[], Quirks::COSMAC_VIP),
+            Err(LoadError::Empty)
+        ));
+        let max = Cpu::max_rom_size(Quirks::COSMAC_VIP);
+        assert!(matches!(
+            Cpu::from_bytes(&vec![0; max + 1], Quirks::COSMAC_VIP),
+            Err(LoadError::TooLarge { size, .. }) if size == max + 1
+        ));
+        assert!(Cpu::from_bytes(&vec![0; max + 1], Quirks::XO_CHIP).is_ok());
+    }
+
+    #[test]
+    fn clear_screen() {
+        let mut cpu = cpu(&[0x00E0]);
+        cpu.display[0] = 1;
+        run(&mut cpu, 1);
+        assert_eq!(pixel(&cpu, 0, 0), 0);
+    }
+
+    #[test]
+    fn call_and_return() {
+        let mut cpu = cpu(&[0x2204, 0x0000, 0x00EE]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.program_counter, 0x204);
+        assert_eq!(cpu.stack, vec![0x202]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.program_counter, 0x202);
+        assert!(cpu.stack.is_empty());
+    }
+
+    #[test]
+    fn return_with_empty_stack_underflows() {
+        let mut cpu = cpu(&[0x00EE]);
+        assert_eq!(cpu.cycle(), Err(CpuError::StackUnderflow { pc: 0x200 }));
+        assert_eq!(cpu.program_counter, 0x200);
+    }
+
+    #[test]
+    fn calls_past_stack_size_overflow() {
+        let mut cpu = cpu(&[0x2200]);
+        run(&mut cpu, STACK_SIZE);
+        assert_eq!(cpu.cycle(), Err(CpuError::StackOverflow { pc: 0x200 }));
+        assert_eq!(cpu.stack.len(), STACK_SIZE);
+    }
+
+    #[test]
+    fn jump() {
+        let mut cpu = cpu(&[0x1ABC]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.program_counter, 0xABC);
+    }
+
+    #[test]
+    fn skips() {
+        // (opcode, vx, vy, skipped)
+        let cases = [
+            (0x3012, 0x12, 0, true),
+            (0x3012, 0x13, 0, false),
+            (0x4012, 0x13, 0, true),
+            (0x4012, 0x12, 0, false),
+            (0x5010, 7, 7, true),
+            (0x5010, 7, 8, false),
+            (0x9010, 7, 8, true),
+            (0x9010, 7, 7, false),
+        ];
+        for (opcode, vx, vy, skipped) in cases.iter() {
+            let mut cpu = cpu(&[*opcode]);
+            cpu.v[0] = *vx;
+            cpu.v[1] = *vy;
+            run(&mut cpu, 1);
+            let expected = if *skipped { 0x204 } else { 0x202 };
+            assert_eq!(cpu.program_counter, expected, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn skip_steps_over_long_load() {
+        let mut cpu = cpu_with(Quirks::XO_CHIP, &[0x3000, 0xF000, 0x1234, 0x00E0]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.program_counter, 0x206);
+    }
+
+    #[test]
+    fn skip_steps_over_f000_as_one_word_without_xo_chip() {
+        let mut cpu = cpu(&[0x3000, 0xF000, 0x1234]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.program_counter, 0x204);
+    }
+
+    #[test]
+    fn skip_in_the_last_word_of_memory_does_not_fault() {
+        for quirks in [Quirks::COSMAC_VIP, Quirks::XO_CHIP] {
+            let mut cpu = cpu_with(quirks, &[0x1200]);
+            let last = cpu.memory.len() - 2;
+            cpu.memory[last..].copy_from_slice(&[0x30, 0x00]);
+            cpu.program_counter = last as u16;
+            assert_eq!(cpu.cycle(), Ok(()));
+            assert_eq!(cpu.program_counter, (last as u16).wrapping_add(4));
+        }
+    }
+
+    #[test]
+    fn load_and_add_immediate() {
+        let mut cpu = cpu(&[0x60FF, 0x7002]);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.v[0], 0x01);
+        // 7XNN never touches the carry flag
+        assert_eq!(cpu.v[V_CARRY_FLAG], 0);
+    }
+
+    #[test]
+    fn register_logic() {
+        let cases = [
+            (0x8010, 0x0F),
+            (0x8011, 0x3F),
+            (0x8012, 0x0C),
+            (0x8013, 0x33),
+        ];
+        for (opcode, expected) in cases.iter() {
+            let mut cpu = cpu(&[*opcode]);
+            cpu.v[0] = 0x3C;
+            cpu.v[1] = 0x0F;
+            run(&mut cpu, 1);
+            assert_eq!(cpu.v[0], *expected, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn logic_resets_vf_only_with_quirk() {
+        for vf_reset in [true, false].iter() {
+            let quirks = Quirks {
+                vf_reset: *vf_reset,
+                ..Quirks::COSMAC_VIP
+            };
+            let mut cpu = cpu_with(quirks, &[0x8011]);
+            cpu.v[V_CARRY_FLAG] = 5;
+            run(&mut cpu, 1);
+            assert_eq!(cpu.v[V_CARRY_FLAG], if *vf_reset { 0 } else { 5 });
+        }
+    }
+
+    #[test]
+    fn add_sets_carry() {
+        // (vx, vy, sum, carry)
+        let cases = [
+            (0xFF, 0x01, 0x00, 1),
+            (0xFF, 0xFF, 0xFE, 1),
+            (0x10, 0x20, 0x30, 0),
+        ];
+        for (vx, vy, sum, carry) in cases.iter() {
+            let mut cpu = cpu(&[0x8014]);
+            cpu.v[0] = *vx;
+            cpu.v[1] = *vy;
+            run(&mut cpu, 1);
+            assert_eq!((cpu.v[0], cpu.v[V_CARRY_FLAG]), (*sum, *carry));
+        }
+    }
+
+    #[test]
+    fn subtract_sets_not_borrow() {
+        // (opcode, vx, vy, difference, not borrow)
+        let cases = [
+            (0x8015, 5, 3, 2, 1),
+            (0x8015, 3, 5, 0xFE, 0),
+            (0x8015, 4, 4, 0, 1),
+            (0x8017, 3, 5, 2, 1),
+            (0x8017, 5, 3, 0xFE, 0),
+            (0x8017, 4, 4, 0, 1),
+        ];
+        for (opcode, vx, vy, difference, not_borrow) in cases.iter() {
+            let mut cpu = cpu(&[*opcode]);
+            cpu.v[0] = *vx;
+            cpu.v[1] = *vy;
+            run(&mut cpu, 1);
+            assert_eq!(
+                (cpu.v[0], cpu.v[V_CARRY_FLAG]),
+                (*difference, *not_borrow),
+                "{:04X} {} {}",
+                opcode,
+                vx,
+                vy
+            );
+        }
+    }
+
+    #[test]
+    fn flag_overwrites_result_when_vf_is_the_destination() {
+        // (opcode, vf, vy, expected vf)
+        let cases = [
+            (0x8F14, 0xFF, 0x01, 1),
+            (0x8F15, 0x05, 0x03, 1),
+            (0x8F17, 0x05, 0x03, 0),
+            (0x8F16, 0x03, 0x03, 1),
+            (0x8F1E, 0x81, 0x81, 1),
+        ];
+        for (opcode, vf, vy, expected) in cases.iter() {
+            let mut cpu = cpu(&[*opcode]);
+            cpu.v[V_CARRY_FLAG] = *vf;
+            cpu.v[1] = *vy;
+            run(&mut cpu, 1);
+            assert_eq!(cpu.v[V_CARRY_FLAG], *expected, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn flag_reads_operands_before_writing_vf() {
+        // VF as the source operand is read before the carry overwrites it
+        let mut cpu = cpu(&[0x80F4]);
+        cpu.v[0] = 0x01;
+        cpu.v[V_CARRY_FLAG] = 0xFF;
+        run(&mut cpu, 1);
+        assert_eq!((cpu.v[0], cpu.v[V_CARRY_FLAG]), (0x00, 1));
+    }
+
+    #[test]
+    fn shifts_use_vy_only_with_quirk() {
+        // (shift_uses_vy, opcode, result, flag)
+        let cases = [
+            (true, 0x8016, 0x40, 1),
+            (false, 0x8016, 0x08, 0),
+            (true, 0x801E, 0x02, 1),
+            (false, 0x801E, 0x20, 0),
+        ];
+        for (shift_uses_vy, opcode, result, flag) in cases.iter() {
+            let quirks = Quirks {
+                shift_uses_vy: *shift_uses_vy,
+                ..Quirks::COSMAC_VIP
+            };
+            let mut cpu = cpu_with(quirks, &[*opcode]);
+            cpu.v[0] = 0x10;
+            cpu.v[1] = 0x81;
+            run(&mut cpu, 1);
+            assert_eq!(
+                (cpu.v[0], cpu.v[V_CARRY_FLAG]),
+                (*result, *flag),
+                "{:04X} {}",
+                opcode,
+                shift_uses_vy
+            );
+        }
+    }
+
+    #[test]
+    fn load_i() {
+        let mut cpu = cpu(&[0xA123]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.i, 0x123);
+    }
+
+    #[test]
+    fn jump_with_offset_uses_vx_only_with_quirk() {
+        for jump_uses_vx in [true, false].iter() {
+            let quirks = Quirks {
+                jump_uses_vx: *jump_uses_vx,
+                ..Quirks::COSMAC_VIP
+            };
+            let mut cpu = cpu_with(quirks, &[0xB210]);
+            cpu.v[0] = 0x01;
+            cpu.v[2] = 0x02;
+            run(&mut cpu, 1);
+            let expected = if *jump_uses_vx { 0x212 } else { 0x211 };
+            assert_eq!(cpu.program_counter, expected);
+        }
+    }
+
+    #[test]
+    fn random_is_masked_and_seeded() {
+        let mut first = cpu(&[0xC00F, 0xC1FF]);
+        let mut second = cpu(&[0xC00F, 0xC1FF]);
+        run(&mut first, 2);
+        run(&mut second, 2);
+        assert_eq!(first.v[0] & 0xF0, 0);
+        assert_eq!(first.v, second.v);
+    }
+
+    #[test]
+    fn draw_sets_pixels_and_collision() {
+        // Glyph 0 of the font is 0xF0 0x90 0x90 0x90 0xF0
+        let mut cpu = cpu(&[0xD015, 0xD015]);
+        cpu.i = FONT_START as u16;
+        cpu.v[0] = 2;
+        cpu.v[1] = 3;
+        run(&mut cpu, 1);
+        assert_eq!(pixel(&cpu, 2, 3), 1);
+        assert_eq!(pixel(&cpu, 5, 3), 1);
+        assert_eq!(pixel(&cpu, 6, 3), 0);
+        assert_eq!(pixel(&cpu, 3, 4), 0);
+        assert_eq!(cpu.v[V_CARRY_FLAG], 0);
+
+        // Drawing again XORs the sprite away and reports the collision
+        run(&mut cpu, 1);
+        assert!(cpu.display.iter().all(|pixel| *pixel == 0));
+        assert_eq!(cpu.v[V_CARRY_FLAG], 1);
+    }
+
+    #[test]
+    fn draw_wraps_starting_coordinates() {
+        let mut cpu = cpu(&[0xD011]);
+        cpu.i = FONT_START as u16;
+        cpu.v[0] = DISPLAY_WIDTH as u8 + 1;
+        cpu.v[1] = DISPLAY_HEIGHT as u8 + 2;
+        run(&mut cpu, 1);
+        assert_eq!(pixel(&cpu, 1, 2), 1);
+    }
+
+    #[test]
+    fn draw_clips_or_wraps_at_edges() {
+        for clip_sprites in [true, false].iter() {
+            let quirks = Quirks {
+                clip_sprites: *clip_sprites,
+                display_wait: false,
+                ..Quirks::COSMAC_VIP
+            };
+            let mut cpu = cpu_with(quirks, &[0xD012]);
+            cpu.i = FONT_START as u16;
+            cpu.v[0] = DISPLAY_WIDTH as u8 - 2;
+            cpu.v[1] = DISPLAY_HEIGHT as u8 - 1;
+            run(&mut cpu, 1);
+            assert_eq!(pixel(&cpu, DISPLAY_WIDTH - 1, DISPLAY_HEIGHT - 1), 1);
+            // The rest of the top row and the second row fall off the screen
+            let wrapped = if *clip_sprites { 0 } else { 1 };
+            assert_eq!(pixel(&cpu, 0, DISPLAY_HEIGHT - 1), wrapped);
+            assert_eq!(pixel(&cpu, DISPLAY_WIDTH - 2, 0), wrapped);
+        }
+    }
+
+    #[test]
+    fn draw_waits_for_vblank_with_quirk() {
+        let mut cpu = cpu_with(Quirks::COSMAC_VIP, &[0xD011, 0xD011, 0x1204]);
+        cpu.i = FONT_START as u16;
+        run(&mut cpu, 1);
+        assert!(cpu.waiting_for_vblank());
+        assert_eq!(cpu.program_counter, 0x200);
+        cpu.run_frame(10).unwrap();
+        assert_eq!(pixel(&cpu, 0, 0), 0);
+
+        // Each frame draws once at its start, then waits for the next
+        cpu.run_frame(10).unwrap();
+        assert_eq!(cpu.program_counter, 0x202);
+        assert_eq!(pixel(&cpu, 0, 0), 1);
+        cpu.run_frame(10).unwrap();
+        assert_eq!(cpu.program_counter, 0x204);
+        assert_eq!(pixel(&cpu, 0, 0), 0);
+        assert!(!cpu.waiting_for_vblank());
+    }
+
+    #[test]
+    fn vip_timing_spends_a_frame_of_machine_cycles() {
+        // 0x200: ADD V0, 1; 0x202: JP 0x200
+        let mut cpu = cpu(&[0x7001, 0x1200]);
+        cpu.run_vip_frame().unwrap();
+        let add = timing::vip_cycles(Instruction::AddImmediate(0, 1), 0, false);
+        let jump = timing::vip_cycles(Instruction::Jump(0x200), 0, false);
+        let loops = timing::VIP_INTERPRETER_CYCLES_PER_FRAME / (add + jump);
+        assert!(cpu.v[0] as u32 == loops || cpu.v[0] as u32 == loops + 1);
+        // Cycles run over carry into the next frame
+        assert!(cpu.vip_cycles <= 0);
+        assert!(cpu.vip_cycles > -((add + jump) as i64));
+    }
+
+    #[test]
+    fn vip_timing_carries_over_through_save_states() {
+        let mut cpu = cpu(&[0x7001, 0x1200]);
+        cpu.run_vip_frame().unwrap();
+        let mut restored = Cpu::from_bytes(&[0], Quirks::COSMAC_VIP).unwrap();
+        restored.load_state(&cpu.save_state()).unwrap();
+        assert_eq!(restored.vip_cycles, cpu.vip_cycles);
+        cpu.run_vip_frame().unwrap();
+        restored.run_vip_frame().unwrap();
+        assert_eq!(restored.save_state(), cpu.save_state());
+    }
+
+    #[test]
+    fn vip_timing_gives_up_the_frame_when_waiting_for_vblank() {
+        let mut cpu = cpu_with(Quirks::COSMAC_VIP, &[0xD015, 0x7001, 0x1202]);
+        cpu.run_vip_frame().unwrap();
+        assert_eq!(cpu.program_counter, 0x200);
+        assert_eq!(cpu.vip_cycles, 0);
+        cpu.run_vip_frame().unwrap();
+        assert!(cpu.v[0] > 0);
+    }
+
+    #[test]
+    fn draw_large_sprite_in_hires() {
+        let mut cpu = cpu_with(Quirks::SUPER_CHIP, &[0x00FF, 0xD010]);
+        cpu.i = 0x300;
+        cpu.memory[0x300] = 0x80;
+        cpu.memory[0x301] = 0x01;
+        cpu.memory[0x31E] = 0xFF;
+        run(&mut cpu, 2);
+        assert_eq!(cpu.display_width(), HIRES_DISPLAY_WIDTH);
+        assert_eq!(pixel(&cpu, 0, 0), 1);
+        assert_eq!(pixel(&cpu, 15, 0), 1);
+        assert_eq!(pixel(&cpu, 7, 15), 1);
+        assert_eq!(pixel(&cpu, 1, 0), 0);
+    }
+
+    #[test]
+    fn draw_to_both_planes_uses_consecutive_sprites() {
+        let mut cpu = cpu_with(Quirks::XO_CHIP, &[0xF301, 0xD001]);
+        cpu.i = 0x300;
+        cpu.memory[0x300] = 0x80;
+        cpu.memory[0x301] = 0x40;
+        run(&mut cpu, 2);
+        assert_eq!(pixel(&cpu, 0, 0), 0b01);
+        assert_eq!(pixel(&cpu, 1, 0), 0b10);
+    }
+
+    #[test]
+    fn key_skips() {
+        // (opcode, pressed, skipped)
+        let cases = [
+            (0xE09E, true, true),
+            (0xE09E, false, false),
+            (0xE0A1, true, false),
+            (0xE0A1, false, true),
+        ];
+        for (opcode, pressed, skipped) in cases.iter() {
+            let mut cpu = cpu(&[*opcode]);
+            cpu.v[0] = 0xA;
+            if *pressed {
+                cpu.set_keys(vec![0xA]);
+            }
+            run(&mut cpu, 1);
+            let expected = if *skipped { 0x204 } else { 0x202 };
+            assert_eq!(cpu.program_counter, expected, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn key_skip_rejects_invalid_key() {
+        let mut cpu = cpu(&[0xE09E]);
+        cpu.v[0] = KEY_COUNT as u8;
+        assert_eq!(
+            cpu.cycle(),
+            Err(CpuError::InvalidKey {
+                pc: 0x200,
+                key: KEY_COUNT as u8
+            })
+        );
+    }
+
+    #[test]
+    fn wait_for_key_blocks_until_pressed() {
+        let quirks = Quirks {
+            wait_key_release: false,
+            ..Quirks::COSMAC_VIP
+        };
+        let mut cpu = cpu_with(quirks, &[0xF30A]);
+        run(&mut cpu, 3);
+        assert_eq!(cpu.program_counter, 0x200);
+        cpu.set_keys(vec![0x7]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.program_counter, 0x202);
+        assert_eq!(cpu.v[3], 0x7);
+    }
+
+    #[test]
+    fn wait_for_key_release_ignores_held_keys() {
+        let mut cpu = cpu(&[0xF30A]);
+        cpu.set_keys(vec![0x2]);
+        run(&mut cpu, 3);
+        assert_eq!(cpu.program_counter, 0x200);
+
+        cpu.set_keys(vec![0x2, 0x7, 0x9]);
+        run(&mut cpu, 3);
+        assert_eq!(cpu.program_counter, 0x200);
+        assert_eq!(cpu.key_wait, KeyWait::Release(0x7));
+
+        // Releasing a different key doesn't end the wait
+        cpu.set_keys(vec![0x7]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.program_counter, 0x200);
+
+        let state = cpu.save_state();
+        cpu.set_keys(vec![]);
+        cpu.load_state(&state).unwrap();
+        cpu.set_keys(vec![]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.program_counter, 0x202);
+        assert_eq!(cpu.v[3], 0x7);
+    }
+
+    #[test]
+    fn timers() {
+        let mut cpu = cpu(&[0xF015, 0xF118, 0xF207]);
+        cpu.v[0] = 3;
+        cpu.v[1] = 1;
+        run(&mut cpu, 2);
+        assert!(cpu.beep());
+        cpu.tick_timers();
+        assert!(!cpu.beep());
+        run(&mut cpu, 1);
+        assert_eq!(cpu.v[2], 2);
+        cpu.tick_timers();
+        cpu.tick_timers();
+        cpu.tick_timers();
+        assert_eq!((cpu.delay_timer, cpu.sound_timer), (0, 0));
+    }
+
+    #[test]
+    fn add_to_i_sets_vf_on_overflow_only_with_quirk() {
+        for i_overflow_sets_vf in [true, false].iter() {
+            let quirks = Quirks {
+                i_overflow_sets_vf: *i_overflow_sets_vf,
+                ..Quirks::COSMAC_VIP
+            };
+            let mut cpu = cpu_with(quirks, &[0xF01E, 0xF01E]);
+            cpu.i = 0xFFE;
+            cpu.v[0] = 1;
+            run(&mut cpu, 1);
+            assert_eq!((cpu.i, cpu.v[V_CARRY_FLAG]), (0xFFF, 0));
+            run(&mut cpu, 1);
+            let flag = if *i_overflow_sets_vf { 1 } else { 0 };
+            assert_eq!((cpu.i, cpu.v[V_CARRY_FLAG]), (0x1000, flag));
+        }
+    }
+
+    #[test]
+    fn font_addresses() {
+        let mut cpu = cpu(&[0xF029, 0xF030]);
+        cpu.v[0] = 0x1A;
+        run(&mut cpu, 1);
+        assert_eq!(cpu.i as usize, FONT_START + (0xA * FONT_HEIGHT));
+        run(&mut cpu, 1);
+        assert_eq!(cpu.i as usize, BIG_FONT_START + (0xA * BIG_FONT_HEIGHT));
+    }
+
+    #[test]
+    fn binary_coded_decimal() {
+        for (value, digits) in [(254, [2, 5, 4]), (7, [0, 0, 7]), (100, [1, 0, 0])].iter() {
+            let mut cpu = cpu(&[0xF033]);
+            cpu.i = 0x300;
+            cpu.v[0] = *value;
+            run(&mut cpu, 1);
+            assert_eq!(&cpu.memory[0x300..0x303], digits);
+            assert_eq!(cpu.i, 0x300);
+        }
+    }
+
+    #[test]
+    fn store_and_load_increment_i_only_with_quirk() {
+        for load_store_increments_i in [true, false].iter() {
+            let quirks = Quirks {
+                load_store_increments_i: *load_store_increments_i,
+                ..Quirks::COSMAC_VIP
+            };
+            let mut cpu = cpu_with(quirks, &[0xF255, 0xA300, 0xF165]);
+            cpu.i = 0x300;
+            cpu.v[..3].copy_from_slice(&[1, 2, 3]);
+            run(&mut cpu, 1);
+            assert_eq!(&cpu.memory[0x300..0x304], &[1, 2, 3, 0]);
+            assert_eq!(
+                cpu.i,
+                if *load_store_increments_i {
+                    0x303
+                } else {
+                    0x300
+                }
+            );
+
+            cpu.v = [0; V_COUNT];
+            run(&mut cpu, 2);
+            assert_eq!(&cpu.v[..3], &[1, 2, 0]);
+            assert_eq!(
+                cpu.i,
+                if *load_store_increments_i {
+                    0x302
+                } else {
+                    0x300
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn store_and_load_increment_i_by_x_on_chip_48() {
+        let mut cpu = cpu_with(Quirks::CHIP_48, &[0xF255, 0xF165]);
+        cpu.i = 0x300;
+        run(&mut cpu, 1);
+        assert_eq!(cpu.i, 0x302);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.i, 0x303);
+    }
+
+    #[test]
+    fn store_past_memory_faults() {
+        let mut cpu = cpu(&[0xF155]);
+        cpu.i = (MEMORY_SIZE - 1) as u16;
+        assert_eq!(
+            cpu.cycle(),
+            Err(CpuError::MemoryOutOfBounds {
+                pc: 0x200,
+                address: MEMORY_SIZE
+            })
+        );
+    }
+
+    #[test]
+    fn register_ranges_go_in_either_direction() {
+        let mut cpu = cpu_with(Quirks::XO_CHIP, &[0x5132, 0x5313, 0x5133]);
+        cpu.i = 0x300;
+        cpu.v[1..4].copy_from_slice(&[1, 2, 3]);
+        run(&mut cpu, 1);
+        assert_eq!(&cpu.memory[0x300..0x303], &[1, 2, 3]);
+        assert_eq!(cpu.i, 0x300);
+
+        cpu.v = [0; V_COUNT];
+        run(&mut cpu, 1);
+        assert_eq!(&cpu.v[1..4], &[3, 2, 1]);
+        run(&mut cpu, 1);
+        assert_eq!(&cpu.v[1..4], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn rpl_flags() {
+        let mut cpu = cpu_with(Quirks::SUPER_CHIP, &[0xF275, 0xF185]);
+        cpu.v[..3].copy_from_slice(&[1, 2, 3]);
+        run(&mut cpu, 1);
+        cpu.v = [0; V_COUNT];
+        run(&mut cpu, 1);
+        assert_eq!(&cpu.v[..3], &[1, 2, 0]);
+    }
+
+    #[test]
+    fn exit_halts() {
+        let mut cpu = cpu(&[0x00FD]);
+        run(&mut cpu, 3);
+        assert!(cpu.halted());
+        assert_eq!(cpu.program_counter, 0x200);
+    }
+
+    #[test]
+    fn resolution_switch_clears_display() {
+        let mut cpu = cpu_with(Quirks::SUPER_CHIP, &[0x00FF, 0x00FE]);
+        cpu.display[0] = 1;
+        run(&mut cpu, 1);
+        assert_eq!(cpu.display_width(), HIRES_DISPLAY_WIDTH);
+        assert_eq!(cpu.display_height(), HIRES_DISPLAY_HEIGHT);
+        assert_eq!(pixel(&cpu, 0, 0), 0);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.display_width(), DISPLAY_WIDTH);
+    }
+
+    #[test]
+    fn scrolling() {
+        // (opcode, destination of the pixel at (10, 10))
+        let cases = [(0x00C3, (10, 13)), (0x00FB, (14, 10)), (0x00FC, (6, 10))];
+        for (opcode, (x, y)) in cases.iter() {
+            let mut cpu = cpu_with(Quirks::SUPER_CHIP, &[*opcode]);
+            cpu.display[10 + (DISPLAY_WIDTH * 10)] = 1;
+            run(&mut cpu, 1);
+            assert_eq!(pixel(&cpu, *x, *y), 1, "{:04X}", opcode);
+            assert_eq!(cpu.display.iter().filter(|pixel| **pixel != 0).count(), 1);
+        }
+    }
+
+    #[test]
+    fn long_load_i() {
+        let mut cpu = cpu_with(Quirks::XO_CHIP, &[0xF000, 0xABCD]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.i, 0xABCD);
+        assert_eq!(cpu.program_counter, 0x204);
+    }
+
+    #[test]
+    fn long_load_i_is_invalid_without_xo_chip() {
+        let mut cpu = cpu(&[0xF000, 0xABCD]);
+        assert_eq!(
+            cpu.cycle(),
+            Err(CpuError::InvalidOpcode {
+                pc: 0x200,
+                opcode: 0xF000
+            })
+        );
+        assert_eq!(cpu.i, 0);
+    }
+
+    #[test]
+    fn audio_pattern_and_pitch() {
+        let mut cpu = cpu_with(Quirks::XO_CHIP, &[0xF002, 0xF03A]);
+        cpu.i = 0x300;
+        cpu.memory[0x300..0x310].copy_from_slice(&[0xAA; AUDIO_PATTERN_SIZE]);
+        cpu.v[0] = 112;
+        run(&mut cpu, 2);
+        assert_eq!(cpu.audio_pattern(), [0xAA; AUDIO_PATTERN_SIZE]);
+        assert!((cpu.audio_playback_rate() - 8000.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn invalid_opcode_faults_without_advancing() {
+        let mut cpu = cpu(&[0x5121]);
+        assert_eq!(
+            cpu.cycle(),
+            Err(CpuError::InvalidOpcode {
+                pc: 0x200,
+                opcode: 0x5121
+            })
+        );
+        assert_eq!(cpu.program_counter, 0x200);
+    }
+
+    #[test]
+    fn save_state_round_trips() {
+        let mut cpu = cpu(&[0x6005, 0xC1FF, 0x2200]);
+        run(&mut cpu, 3);
+        let state = cpu.save_state();
+        let mut restored = Cpu::from_bytes(&[0], Quirks::COSMAC_VIP).unwrap();
+        restored.load_state(&state).unwrap();
+        run(&mut cpu, 2);
+        run(&mut restored, 2);
+        assert_eq!(cpu.save_state(), restored.save_state());
+    }
+
+    #[test]
+    fn load_state_rejects_damaged_states() {
+        let mut saved = cpu(&[0x6005, 0x6107]);
+        run(&mut saved, 2);
+        let state = saved.save_state();
+
+        let mut bad_magic = state.clone();
+        bad_magic[0] = b'X';
+        let mut newer = state.clone();
+        newer[MAGIC.len()] = VERSION + 1;
+        let mut trailing = state.clone();
+        trailing.push(0);
+
+        let cases = [
+            (&bad_magic[..], StateError::BadMagic),
+            (&state[..2], StateError::BadMagic),
+            (&newer[..], StateError::UnsupportedVersion(VERSION + 1)),
+            (&state[..MAGIC.len() + 1], StateError::Truncated),
+            (&state[..state.len() - 1], StateError::Truncated),
+            (&trailing[..], StateError::Invalid("trailing data")),
+        ];
+        let mut restored = cpu(&[0x6301]);
+        run(&mut restored, 1);
+        for (damaged, err) in cases {
+            assert_eq!(restored.load_state(damaged), Err(err.clone()), "{}", err);
+            // A state that can't be read leaves the cpu as it was
+            assert_eq!(restored.v()[3], 1);
+            assert_eq!(restored.program_counter(), 0x202);
+        }
     }
 }
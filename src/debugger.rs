@@ -0,0 +1,645 @@
+use crate::chip8::{AccessKind, Cpu, CpuError};
+use crate::instruction::{decode, Instruction};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// Which kinds of memory access trigger a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Watch {
+    fn matches(self, kind: AccessKind) -> bool {
+        matches!(
+            (self, kind),
+            (Watch::ReadWrite, _)
+                | (Watch::Read, AccessKind::Read)
+                | (Watch::Write, AccessKind::Write)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    V(u8),
+    I,
+    ProgramCounter,
+    DelayTimer,
+    SoundTimer,
+}
+
+impl Register {
+    fn value(self, cpu: &Cpu) -> u16 {
+        match self {
+            Register::V(index) => cpu.v()[index as usize] as u16,
+            Register::I => cpu.i(),
+            Register::ProgramCounter => cpu.program_counter(),
+            Register::DelayTimer => cpu.delay_timer() as u16,
+            Register::SoundTimer => cpu.sound_timer() as u16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    fn compare(self, left: u16, right: u16) -> bool {
+        match self {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+        }
+    }
+}
+
+/// Breaks when a register comparison becomes true.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterCondition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl RegisterCondition {
+    fn holds(&self, cpu: &Cpu) -> bool {
+        self.comparison
+            .compare(self.register.value(cpu), self.value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Breakpoint(u16),
+    Watchpoint { address: usize, kind: AccessKind },
+    Condition(RegisterCondition),
+    Step,
+    Fault(CpuError),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Breakpoint(address) => write!(f, "breakpoint at 0x{:04X}", address),
+            StopReason::Watchpoint { address, kind } => {
+                write!(f, "watchpoint: {:?} of 0x{:04X}", kind, address)
+            }
+            StopReason::Condition(condition) => write!(f, "condition {} is true", condition),
+            StopReason::Step => write!(f, "step"),
+            StopReason::Fault(err) => write!(f, "fault: {}", err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Paused,
+    Running,
+    Step,
+    /// Run until the stack is back to the given depth.
+    StepOver(usize),
+    /// Run until the stack is shallower than the given depth.
+    StepOut(usize),
+}
+
+/// Controls execution of a [`Cpu`] with breakpoints, watchpoints and stepping.
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    watchpoints: BTreeMap<usize, Watch>,
+    conditions: Vec<(RegisterCondition, bool)>,
+    mode: Mode,
+    resuming: bool,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            conditions: Vec::new(),
+            mode: Mode::Running,
+            resuming: false,
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.mode == Mode::Paused
+    }
+
+    pub fn pause(&mut self) {
+        self.mode = Mode::Paused;
+    }
+
+    pub fn resume(&mut self) {
+        self.start(Mode::Running);
+    }
+
+    pub fn step(&mut self) {
+        self.start(Mode::Step);
+    }
+
+    /// Steps over a 2NNN call by running until it returns, otherwise steps once.
+    pub fn step_over(&mut self, cpu: &Cpu) {
+        let pc = cpu.program_counter() as usize;
+        let opcode = match cpu.memory().get(pc..(pc + 2)) {
+            Some(bytes) => ((bytes[0] as u16) << 8) | bytes[1] as u16,
+            None => 0,
+        };
+        match decode(opcode) {
+            Instruction::Call(_) => self.start(Mode::StepOver(cpu.stack().len())),
+            _ => self.start(Mode::Step),
+        }
+    }
+
+    /// Runs until the current subroutine returns with 00EE.
+    pub fn step_out(&mut self, cpu: &Cpu) {
+        self.start(Mode::StepOut(cpu.stack().len()));
+    }
+
+    fn start(&mut self, mode: Mode) {
+        self.mode = mode;
+        // Don't immediately stop again on the breakpoint we are paused at
+        self.resuming = true;
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, Watch)> + '_ {
+        self.watchpoints
+            .iter()
+            .map(|(address, watch)| (*address, *watch))
+    }
+
+    pub fn add_watchpoint(&mut self, address: usize, watch: Watch) {
+        self.watchpoints.insert(address, watch);
+    }
+
+    pub fn remove_watchpoint(&mut self, address: usize) -> bool {
+        self.watchpoints.remove(&address).is_some()
+    }
+
+    pub fn conditions(&self) -> impl Iterator<Item = RegisterCondition> + '_ {
+        self.conditions.iter().map(|(condition, _)| *condition)
+    }
+
+    pub fn add_condition(&mut self, condition: RegisterCondition, cpu: &Cpu) {
+        self.conditions.push((condition, condition.holds(cpu)));
+    }
+
+    pub fn remove_condition(&mut self, index: usize) -> Option<RegisterCondition> {
+        match index < self.conditions.len() {
+            true => Some(self.conditions.remove(index).0),
+            false => None,
+        }
+    }
+
+    /// Executes up to `cycles` instructions, stopping early when the debugger pauses.
+    /// Returns why execution stopped, if it did.
+    pub fn run(&mut self, cpu: &mut Cpu, cycles: u32) -> Option<StopReason> {
+        cpu.set_memory_tracing(!self.watchpoints.is_empty());
+        for _ in 0..cycles {
+            if self.paused() {
+                return None;
+            }
+            if let Some(reason) = self.cycle(cpu) {
+                self.mode = Mode::Paused;
+                return Some(reason);
+            }
+            if cpu.waiting_for_vblank() {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn cycle(&mut self, cpu: &mut Cpu) -> Option<StopReason> {
+        let pc = cpu.program_counter();
+        if !self.resuming && self.breakpoints.contains(&pc) {
+            return Some(StopReason::Breakpoint(pc));
+        }
+        self.resuming = false;
+
+        if let Err(err) = cpu.cycle() {
+            return Some(StopReason::Fault(err));
+        }
+        // DXYN hasn't run until the vertical blank, so a step isn't over yet
+        if cpu.waiting_for_vblank() {
+            return None;
+        }
+
+        let watched = cpu.take_memory_accesses().into_iter().find(|access| {
+            self.watchpoints
+                .get(&access.address)
+                .is_some_and(|watch| watch.matches(access.kind))
+        });
+        if let Some(access) = watched {
+            return Some(StopReason::Watchpoint {
+                address: access.address,
+                kind: access.kind,
+            });
+        }
+
+        let mut triggered = None;
+        for (condition, held) in self.conditions.iter_mut() {
+            let holds = condition.holds(cpu);
+            if holds && !*held && triggered.is_none() {
+                triggered = Some(*condition);
+            }
+            *held = holds;
+        }
+        if let Some(condition) = triggered {
+            return Some(StopReason::Condition(condition));
+        }
+
+        let depth = cpu.stack().len();
+        match self.mode {
+            Mode::Step => Some(StopReason::Step),
+            Mode::StepOver(target) if depth <= target => Some(StopReason::Step),
+            Mode::StepOut(target) if depth < target => Some(StopReason::Step),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Register::V(index) => write!(f, "v{:X}", index),
+            Register::I => write!(f, "i"),
+            Register::ProgramCounter => write!(f, "pc"),
+            Register::DelayTimer => write!(f, "dt"),
+            Register::SoundTimer => write!(f, "st"),
+        }
+    }
+}
+
+impl FromStr for Register {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "i" => Ok(Register::I),
+            "pc" => Ok(Register::ProgramCounter),
+            "dt" => Ok(Register::DelayTimer),
+            "st" => Ok(Register::SoundTimer),
+            name => match name
+                .strip_prefix('v')
+                .map(|index| u8::from_str_radix(index, 16))
+            {
+                Some(Ok(index)) if index < 16 => Ok(Register::V(index)),
+                _ => Err(format!("unknown register '{}'", s)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+impl FromStr for Comparison {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "==" => Ok(Comparison::Equal),
+            "!=" => Ok(Comparison::NotEqual),
+            "<" => Ok(Comparison::Less),
+            "<=" => Ok(Comparison::LessOrEqual),
+            ">" => Ok(Comparison::Greater),
+            ">=" => Ok(Comparison::GreaterOrEqual),
+            _ => Err(format!("unknown comparison '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for RegisterCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} 0x{:X}",
+            self.register, self.comparison, self.value
+        )
+    }
+}
+
+impl FromStr for Watch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "r" => Ok(Watch::Read),
+            "w" => Ok(Watch::Write),
+            "rw" => Ok(Watch::ReadWrite),
+            _ => Err(format!("unknown watch kind '{}', expected r, w or rw", s)),
+        }
+    }
+}
+
+/// A command entered at the debugger prompt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Continue,
+    Pause,
+    Step,
+    StepOver,
+    StepOut,
+    Break(u16),
+    Delete(u16),
+    Watch(usize, Watch),
+    Unwatch(usize),
+    Condition(RegisterCondition),
+    DeleteCondition(usize),
+    Registers,
+    Memory(usize, usize),
+    List,
+    Help,
+}
+
+impl Command {
+    pub const HELP: &'static str = "\
+c | continue             resume execution
+p | pause                pause execution
+s | step                 execute one instruction
+n | next                 step over a 2NNN call
+o | out                  run until the current subroutine returns
+b <addr>                 add a breakpoint
+d <addr>                 delete a breakpoint
+w <addr> [r|w|rw]        watch memory reads and/or writes (default rw)
+uw <addr>                delete a watchpoint
+cond <reg> <op> <value>  break when e.g. `v3 == 0x10` or `i >= 0x300` becomes true
+dc <index>               delete a condition
+r | regs                 show registers
+m <addr> [len]           dump memory
+l | list                 list breakpoints, watchpoints and conditions
+h | help                 show this help";
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let command = match words.as_slice() {
+            ["c"] | ["continue"] => Command::Continue,
+            ["p"] | ["pause"] => Command::Pause,
+            ["s"] | ["step"] => Command::Step,
+            ["n"] | ["next"] => Command::StepOver,
+            ["o"] | ["out"] => Command::StepOut,
+            ["b", address] => Command::Break(parse_word(address)?),
+            ["d", address] => Command::Delete(parse_word(address)?),
+            ["w", address] => Command::Watch(parse_number(address)?, Watch::ReadWrite),
+            ["w", address, watch] => Command::Watch(parse_number(address)?, watch.parse()?),
+            ["uw", address] => Command::Unwatch(parse_number(address)?),
+            ["cond", register, comparison, value] => Command::Condition(RegisterCondition {
+                register: register.parse()?,
+                comparison: comparison.parse()?,
+                value: parse_word(value)?,
+            }),
+            ["dc", index] => Command::DeleteCondition(parse_number(index)?),
+            ["r"] | ["regs"] => Command::Registers,
+            ["m", address] => Command::Memory(parse_number(address)?, 16),
+            ["m", address, len] => Command::Memory(parse_number(address)?, parse_number(len)?),
+            ["l"] | ["list"] => Command::List,
+            ["h"] | ["help"] => Command::Help,
+            _ => return Err(format!("unknown command '{}', try 'help'", s.trim())),
+        };
+        Ok(command)
+    }
+}
+
+/// Parses decimal numbers, or hexadecimal ones prefixed with `0x` or `$`.
+fn parse_number(s: &str) -> Result<usize, String> {
+    let result = match s.strip_prefix("0x").or_else(|| s.strip_prefix('$')) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    result.map_err(|_| format!("invalid number '{}'", s))
+}
+
+/// Like [`parse_number`], for values that must fit in 16 bits.
+fn parse_word(s: &str) -> Result<u16, String> {
+    u16::try_from(parse_number(s)?).map_err(|_| format!("'{}' is larger than 0xFFFF", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Quirks;
+
+    /// Stores and loads v0 at 0x300, then calls a subroutine at 0x210 that
+    /// counts v1 up to 8.
+    fn cpu() -> Cpu {
+        let rom = [
+            0xA3, 0x00, // 0x200: LD I, 0x300
+            0x60, 0x05, // 0x202: LD V0, 5
+            0xF0, 0x55, // 0x204: LD [I], V0
+            0xF0, 0x65, // 0x206: LD V0, [I]
+            0x22, 0x10, // 0x208: CALL 0x210
+            0x70, 0x01, // 0x20A: ADD V0, 1
+            0x12, 0x0C, // 0x20C: JP 0x20C
+            0x00, 0x00, //
+            0x61, 0x07, // 0x210: LD V1, 7
+            0x71, 0x01, // 0x212: ADD V1, 1
+            0x00, 0xEE, // 0x214: RET
+        ];
+        Cpu::from_bytes(&rom, Quirks::SUPER_CHIP).unwrap()
+    }
+
+    /// Runs to a breakpoint at `address`, then removes it.
+    fn run_to(debugger: &mut Debugger, cpu: &mut Cpu, address: u16) {
+        debugger.add_breakpoint(address);
+        assert_eq!(
+            debugger.run(cpu, 100),
+            Some(StopReason::Breakpoint(address))
+        );
+        debugger.remove_breakpoint(address);
+    }
+
+    #[test]
+    fn resumes_past_the_breakpoint_it_stopped_at() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0x204);
+        debugger.add_breakpoint(0x20A);
+        assert_eq!(
+            debugger.run(&mut cpu, 100),
+            Some(StopReason::Breakpoint(0x204))
+        );
+        assert!(debugger.paused());
+        assert_eq!(debugger.run(&mut cpu, 100), None);
+        assert_eq!(cpu.program_counter(), 0x204);
+
+        debugger.resume();
+        assert_eq!(
+            debugger.run(&mut cpu, 100),
+            Some(StopReason::Breakpoint(0x20A))
+        );
+    }
+
+    #[test]
+    fn step_over_runs_a_call_until_it_returns() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::new();
+        run_to(&mut debugger, &mut cpu, 0x208);
+        debugger.step_over(&cpu);
+        assert_eq!(debugger.run(&mut cpu, 100), Some(StopReason::Step));
+        assert_eq!(cpu.program_counter(), 0x20A);
+        assert_eq!(cpu.v()[1], 8);
+
+        debugger.step_over(&cpu);
+        assert_eq!(debugger.run(&mut cpu, 100), Some(StopReason::Step));
+        assert_eq!(cpu.program_counter(), 0x20C);
+    }
+
+    #[test]
+    fn step_out_runs_until_the_subroutine_returns() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::new();
+        run_to(&mut debugger, &mut cpu, 0x212);
+        debugger.step_out(&cpu);
+        assert_eq!(debugger.run(&mut cpu, 100), Some(StopReason::Step));
+        assert_eq!(cpu.program_counter(), 0x20A);
+        assert!(cpu.stack().is_empty());
+    }
+
+    #[test]
+    fn watchpoints_stop_after_matching_accesses() {
+        let cases = [
+            (Watch::Write, AccessKind::Write, 0x206),
+            (Watch::Read, AccessKind::Read, 0x208),
+            (Watch::ReadWrite, AccessKind::Write, 0x206),
+        ];
+        for (watch, kind, pc) in cases {
+            let mut cpu = cpu();
+            let mut debugger = Debugger::new();
+            debugger.add_watchpoint(0x300, watch);
+            assert_eq!(
+                debugger.run(&mut cpu, 100),
+                Some(StopReason::Watchpoint {
+                    address: 0x300,
+                    kind
+                }),
+                "{:?}",
+                watch
+            );
+            assert_eq!(cpu.program_counter(), pc, "{:?}", watch);
+        }
+    }
+
+    #[test]
+    fn conditions_stop_only_when_they_become_true() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::new();
+        let condition = RegisterCondition {
+            register: Register::V(0),
+            comparison: Comparison::GreaterOrEqual,
+            value: 5,
+        };
+        debugger.add_condition(condition, &cpu);
+        assert_eq!(
+            debugger.run(&mut cpu, 100),
+            Some(StopReason::Condition(condition))
+        );
+        assert_eq!(cpu.program_counter(), 0x204);
+
+        // v0 stays at 5 or more from here on
+        debugger.resume();
+        assert_eq!(debugger.run(&mut cpu, 100), None);
+        assert!(!debugger.paused());
+    }
+
+    #[test]
+    fn conditions_already_true_when_added_do_not_stop() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::new();
+        let condition = RegisterCondition {
+            register: Register::I,
+            comparison: Comparison::Less,
+            value: 0x400,
+        };
+        debugger.add_condition(condition, &cpu);
+        assert_eq!(debugger.run(&mut cpu, 100), None);
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!("c".parse(), Ok(Command::Continue));
+        assert_eq!(" next ".parse(), Ok(Command::StepOver));
+        assert_eq!("b 0x204".parse(), Ok(Command::Break(0x204)));
+        assert_eq!("d $20A".parse(), Ok(Command::Delete(0x20A)));
+        assert_eq!("w 768".parse(), Ok(Command::Watch(0x300, Watch::ReadWrite)));
+        assert_eq!("w 0x300 r".parse(), Ok(Command::Watch(0x300, Watch::Read)));
+        assert_eq!("m 0x300".parse(), Ok(Command::Memory(0x300, 16)));
+        assert_eq!(
+            "cond VA != 0x10".parse(),
+            Ok(Command::Condition(RegisterCondition {
+                register: Register::V(0xA),
+                comparison: Comparison::NotEqual,
+                value: 0x10,
+            }))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        let invalid = [
+            "jump",
+            "b",
+            "b 0x10200",
+            "b 0xZZ",
+            "w 0x300 x",
+            "cond vG == 1",
+            "cond v0 =< 1",
+            "cond v0 == 0x10000",
+        ];
+        for command in invalid {
+            assert!(command.parse::<Command>().is_err(), "{}", command);
+        }
+        assert_eq!(
+            "b 0x10200".parse::<Command>(),
+            Err("'0x10200' is larger than 0xFFFF".to_string())
+        );
+    }
+}
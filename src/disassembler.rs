@@ -0,0 +1,286 @@
+use crate::instruction::{decode, Instruction, Syntax};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+const PROGRAM_START: usize = 0x200;
+const DATA_BYTES_PER_LINE: usize = 8;
+const INDENT: &str = "    ";
+
+/// Why an address is referenced. When several instructions refer to the same
+/// address the later variants win, so a called routine is named `sub_`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Label {
+    Data,
+    Code,
+    Subroutine,
+}
+
+impl Label {
+    fn name(self, address: usize) -> String {
+        let prefix = match self {
+            Label::Data => "data",
+            Label::Code => "label",
+            Label::Subroutine => "sub",
+        };
+        format!("{}_{:03X}", prefix, address)
+    }
+}
+
+/// Disassembles a ROM loaded at 0x200 into a listing.
+///
+/// Code is found by recursive descent from the entry point, following jumps,
+/// calls and both sides of every skip. Referenced addresses are given labels
+/// and anything that is never reached as code is emitted as data bytes, so
+/// assembling the listing reproduces the ROM exactly.
+pub fn disassemble(rom: &[u8], syntax: Syntax) -> String {
+    let disassembly = Disassembly::trace(rom);
+    let mut listing = String::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let address = PROGRAM_START + offset;
+        if let Some(label) = disassembly.label(address) {
+            match syntax {
+                Syntax::Cowgod => writeln!(listing, "{}:", label),
+                Syntax::Octo => writeln!(listing, ": {}", label),
+            }
+            .unwrap();
+        }
+        match disassembly.code.get(&address) {
+            Some(instruction) => {
+                let operand = disassembly.operand(instruction, address);
+                let formatted = instruction.display(syntax);
+                let formatted = match &operand {
+                    Some(operand) => formatted.with_operand(operand),
+                    None => formatted,
+                };
+                writeln!(listing, "{}{}", INDENT, formatted).unwrap();
+                offset += instruction.size();
+            }
+            None => {
+                let len = (1..DATA_BYTES_PER_LINE)
+                    .take_while(|len| {
+                        offset + len < rom.len()
+                            && !disassembly.owned[offset + len]
+                            && disassembly.label(address + len).is_none()
+                    })
+                    .count()
+                    + 1;
+                let bytes = rom[offset..(offset + len)]
+                    .iter()
+                    .map(|byte| format!("0x{:02X}", byte));
+                let line = match syntax {
+                    Syntax::Cowgod => format!("DB {}", bytes.collect::<Vec<_>>().join(", ")),
+                    Syntax::Octo => bytes.collect::<Vec<_>>().join(" "),
+                };
+                writeln!(listing, "{}{}", INDENT, line).unwrap();
+                offset += len;
+            }
+        }
+    }
+    listing
+}
+
+/// Instructions found by tracing a ROM the way [`disassemble`] does, by
+/// address.
+pub fn trace(rom: &[u8]) -> BTreeMap<usize, Instruction> {
+    Disassembly::trace(rom).code
+}
+
+struct Disassembly<'a> {
+    rom: &'a [u8],
+    code: BTreeMap<usize, Instruction>,
+    /// Bytes, by ROM offset, that belong to a traced instruction
+    owned: Vec<bool>,
+    labels: BTreeMap<usize, Label>,
+}
+
+impl<'a> Disassembly<'a> {
+    fn trace(rom: &'a [u8]) -> Self {
+        let mut disassembly = Self {
+            rom,
+            code: BTreeMap::new(),
+            owned: vec![false; rom.len()],
+            labels: BTreeMap::new(),
+        };
+        let mut pending = vec![PROGRAM_START];
+        while let Some(address) = pending.pop() {
+            disassembly.trace_from(address, &mut pending);
+        }
+        disassembly
+    }
+
+    /// Follows straight line code from `address`, queueing any branch targets.
+    fn trace_from(&mut self, mut address: usize, pending: &mut Vec<usize>) {
+        while let Some(instruction) = self.claim(address) {
+            match instruction {
+                Instruction::Jump(target) => {
+                    self.add_label(target as usize, Label::Code);
+                    pending.push(target as usize);
+                    return;
+                }
+                Instruction::Call(target) => {
+                    self.add_label(target as usize, Label::Subroutine);
+                    pending.push(target as usize);
+                }
+                // The offset comes from a register, so only the table base is known
+                Instruction::JumpOffset(target) => {
+                    self.add_label(target as usize, Label::Code);
+                    return;
+                }
+                Instruction::LoadI(target) => self.add_label(target as usize, Label::Data),
+                Instruction::LongLoadI => {
+                    if let Some(target) = self.word(address + 2) {
+                        self.add_label(target as usize, Label::Data);
+                    }
+                }
+                Instruction::Return | Instruction::Exit => return,
+                _ if instruction.is_skip() => {
+                    let skipped = match self.word(address + 2).map(decode) {
+                        Some(next) => next.size(),
+                        None => 2,
+                    };
+                    pending.push(address + 2 + skipped);
+                }
+                _ => {}
+            }
+            address += instruction.size();
+        }
+    }
+
+    /// Decodes the instruction at `address` and marks its bytes as code, unless
+    /// it is invalid, runs off the ROM or overlaps an instruction already traced.
+    fn claim(&mut self, address: usize) -> Option<Instruction> {
+        if self.code.contains_key(&address) {
+            return None;
+        }
+        let instruction = decode(self.word(address)?);
+        if let Instruction::Unknown(_) = instruction {
+            return None;
+        }
+        let start = address.checked_sub(PROGRAM_START)?;
+        let owned = self.owned.get_mut(start..(start + instruction.size()))?;
+        if owned.iter().any(|owned| *owned) {
+            return None;
+        }
+        owned.iter_mut().for_each(|owned| *owned = true);
+        self.code.insert(address, instruction);
+        Some(instruction)
+    }
+
+    fn word(&self, address: usize) -> Option<u16> {
+        let offset = address.checked_sub(PROGRAM_START)?;
+        let bytes = self.rom.get(offset..(offset + 2))?;
+        Some(((bytes[0] as u16) << 8) | bytes[1] as u16)
+    }
+
+    fn add_label(&mut self, address: usize, label: Label) {
+        let entry = self.labels.entry(address).or_insert(label);
+        *entry = (*entry).max(label);
+    }
+
+    /// Name of the label at `address`, if one can be placed there. Addresses
+    /// outside the ROM or in the middle of an instruction stay numeric.
+    fn label(&self, address: usize) -> Option<String> {
+        let label = self.labels.get(&address)?;
+        let offset = address.checked_sub(PROGRAM_START)?;
+        let placeable =
+            self.code.contains_key(&address) || (offset < self.rom.len() && !self.owned[offset]);
+        match placeable {
+            true => Some(label.name(address)),
+            false => None,
+        }
+    }
+
+    fn operand(&self, instruction: &Instruction, address: usize) -> Option<String> {
+        match instruction {
+            Instruction::LongLoadI => {
+                let target = self.word(address + 2)?;
+                Some(
+                    self.label(target as usize)
+                        .unwrap_or_else(|| format!("0x{:04X}", target)),
+                )
+            }
+            _ => self.label(instruction.address()? as usize),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Calls a subroutine, skips over a jump, then loops forever. The sprite
+    /// at the end is only referenced by LD I.
+    const ROM: [u8; 14] = [
+        0x22, 0x08, // 0x200: CALL 0x208
+        0x30, 0x00, // 0x202: SE V0, 0
+        0x12, 0x02, // 0x204: JP 0x202
+        0x12, 0x06, // 0x206: JP 0x206
+        0xA2, 0x0C, // 0x208: LD I, 0x20C
+        0x00, 0xEE, // 0x20A: RET
+        0xF0, 0x90, // 0x20C: sprite
+    ];
+
+    #[test]
+    fn traces_calls_jumps_and_both_sides_of_skips() {
+        let code = trace(&ROM);
+        assert_eq!(
+            code.keys().copied().collect::<Vec<_>>(),
+            [0x200, 0x202, 0x204, 0x206, 0x208, 0x20A]
+        );
+        assert_eq!(code[&0x208], Instruction::LoadI(0x20C));
+    }
+
+    #[test]
+    fn trace_skips_data_after_a_jump() {
+        // 0x200: LD I, 0x206; 0x202: DRW V0, V0, 1; 0x204: JP 0x204; 0x206: data
+        let rom = [0xA2, 0x06, 0xD0, 0x01, 0x12, 0x04, 0xFF, 0xFF];
+        let code = trace(&rom);
+        assert_eq!(
+            code.keys().copied().collect::<Vec<_>>(),
+            [0x200, 0x202, 0x204]
+        );
+        assert_eq!(code[&0x204], Instruction::Jump(0x204));
+    }
+
+    #[test]
+    fn lists_labels_and_data() {
+        assert_eq!(
+            disassemble(&ROM, Syntax::Cowgod),
+            "    CALL sub_208
+label_202:
+    SE V0, 0x00
+    JP label_202
+label_206:
+    JP label_206
+sub_208:
+    LD I, data_20C
+    RET
+data_20C:
+    DB 0xF0, 0x90
+"
+        );
+        assert_eq!(
+            disassemble(&ROM, Syntax::Octo),
+            "    :call sub_208
+: label_202
+    if v0 != 0x00 then
+    jump label_202
+: label_206
+    jump label_206
+: sub_208
+    i := data_20C
+    return
+: data_20C
+    0xF0 0x90
+"
+        );
+    }
+
+    #[test]
+    fn unreached_bytes_are_data_even_if_they_decode() {
+        // 0x200: JP 0x200, then bytes that would decode as CLS
+        let listing = disassemble(&[0x12, 0x00, 0x00, 0xE0], Syntax::Cowgod);
+        assert!(listing.ends_with("    DB 0x00, 0xE0\n"), "{}", listing);
+    }
+}
@@ -0,0 +1,529 @@
+use crate::keymap::Keymap;
+use std::io;
+
+#[cfg(target_os = "linux")]
+pub use evdev::Evdev;
+
+/// Gamepad buttons, named for keymaps by prefixing `Pad`, as in `PadSouth` or
+/// `PadLeftStickUp`. Stick and trigger axes act as buttons once pushed past
+/// halfway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Button {
+    South,
+    East,
+    North,
+    West,
+    LeftBumper,
+    RightBumper,
+    LeftTrigger,
+    RightTrigger,
+    Select,
+    Start,
+    Mode,
+    LeftThumb,
+    RightThumb,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+    LeftStickUp,
+    LeftStickDown,
+    LeftStickLeft,
+    LeftStickRight,
+    RightStickUp,
+    RightStickDown,
+    RightStickLeft,
+    RightStickRight,
+}
+
+impl Button {
+    /// The host key name a keymap binds this button by.
+    pub fn name(self) -> String {
+        format!("Pad{:?}", self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ButtonEvent {
+    pub button: Button,
+    pub pressed: bool,
+}
+
+/// A source of gamepad button presses and releases.
+pub trait InputBackend {
+    /// Appends the button changes since the last poll to `events`.
+    fn poll(&mut self, events: &mut Vec<ButtonEvent>) -> io::Result<()>;
+}
+
+impl<B: InputBackend + ?Sized> InputBackend for Box<B> {
+    fn poll(&mut self, events: &mut Vec<ButtonEvent>) -> io::Result<()> {
+        (**self).poll(events)
+    }
+}
+
+/// A backend fed by hand, for hosts and tests without a physical gamepad.
+#[derive(Debug, Clone, Default)]
+pub struct Mock {
+    queued: Vec<ButtonEvent>,
+}
+
+impl Mock {
+    pub fn press(&mut self, button: Button) {
+        self.queued.push(ButtonEvent {
+            button,
+            pressed: true,
+        });
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.queued.push(ButtonEvent {
+            button,
+            pressed: false,
+        });
+    }
+}
+
+impl InputBackend for Mock {
+    fn poll(&mut self, events: &mut Vec<ButtonEvent>) -> io::Result<()> {
+        events.append(&mut self.queued);
+        Ok(())
+    }
+}
+
+/// Tracks which buttons are held on a backend's gamepads.
+#[derive(Debug, Default)]
+pub struct Gamepad<B> {
+    backend: B,
+    /// Held buttons, once for every gamepad holding them.
+    held: Vec<Button>,
+    events: Vec<ButtonEvent>,
+}
+
+impl<B: InputBackend> Gamepad<B> {
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            held: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Applies the backend's button changes since the last poll.
+    pub fn poll(&mut self) -> io::Result<()> {
+        self.backend.poll(&mut self.events)?;
+        for event in self.events.drain(..) {
+            match event.pressed {
+                true => self.held.push(event.button),
+                false => {
+                    if let Some(index) = self.held.iter().position(|b| *b == event.button) {
+                        self.held.swap_remove(index);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_held(&self, button: Button) -> bool {
+        self.held.contains(&button)
+    }
+
+    /// The CHIP-8 keys bound to held buttons, for [`Cpu::set_keys`](crate::chip8::Cpu::set_keys).
+    pub fn keys(&self, keymap: &Keymap) -> Vec<usize> {
+        self.held
+            .iter()
+            .filter_map(|button| keymap.key(&button.name()))
+            .map(usize::from)
+            .collect()
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod evdev {
+    use super::{Button, ButtonEvent, InputBackend};
+    use std::collections::HashMap;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Read};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    const INPUT_DIR: &str = "/dev/input";
+    const EVENT_PREFIX: &str = "event";
+    const NAME_LEN: usize = 256;
+    const EVENTS_PER_READ: usize = 64;
+    const EVENT_SIZE: usize = std::mem::size_of::<libc::input_event>();
+    const EVENT_TIME_SIZE: usize = std::mem::size_of::<libc::timeval>();
+
+    const IOC_READ: libc::c_ulong = 2;
+    const EVIOCGNAME: libc::c_ulong = 0x06;
+    const EVIOCGBIT: libc::c_ulong = 0x20;
+    const EVIOCGABS: libc::c_ulong = 0x40;
+
+    const EV_KEY: u16 = 0x01;
+    const EV_ABS: u16 = 0x03;
+    const KEY_MAX: usize = 0x2FF;
+    const BTN_JOYSTICK: usize = 0x120;
+    const BTN_GAMEPAD: usize = 0x130;
+
+    const ABS_X: u16 = 0x00;
+    const ABS_Y: u16 = 0x01;
+    const ABS_Z: u16 = 0x02;
+    const ABS_RX: u16 = 0x03;
+    const ABS_RY: u16 = 0x04;
+    const ABS_RZ: u16 = 0x05;
+    const ABS_HAT0X: u16 = 0x10;
+    const ABS_HAT0Y: u16 = 0x11;
+    const AXES: [u16; 8] = [
+        ABS_X, ABS_Y, ABS_Z, ABS_RX, ABS_RY, ABS_RZ, ABS_HAT0X, ABS_HAT0Y,
+    ];
+
+    /// Linux key codes of gamepad buttons, with a plain joystick's first four
+    /// buttons doubling as the face buttons.
+    const BUTTONS: [(u16, Button); 21] = [
+        (0x120, Button::South),
+        (0x121, Button::East),
+        (0x122, Button::West),
+        (0x123, Button::North),
+        (0x130, Button::South),
+        (0x131, Button::East),
+        (0x133, Button::North),
+        (0x134, Button::West),
+        (0x136, Button::LeftBumper),
+        (0x137, Button::RightBumper),
+        (0x138, Button::LeftTrigger),
+        (0x139, Button::RightTrigger),
+        (0x13A, Button::Select),
+        (0x13B, Button::Start),
+        (0x13C, Button::Mode),
+        (0x13D, Button::LeftThumb),
+        (0x13E, Button::RightThumb),
+        (0x220, Button::DPadUp),
+        (0x221, Button::DPadDown),
+        (0x222, Button::DPadLeft),
+        (0x223, Button::DPadRight),
+    ];
+
+    /// How an absolute axis maps onto buttons.
+    enum Axis {
+        /// Pushing towards the minimum or maximum presses the first or second.
+        Stick(Button, Button),
+        /// Pulling from the minimum towards the maximum presses it.
+        Trigger(Button),
+    }
+
+    fn axis(code: u16) -> Option<Axis> {
+        match code {
+            ABS_X => Some(Axis::Stick(Button::LeftStickLeft, Button::LeftStickRight)),
+            ABS_Y => Some(Axis::Stick(Button::LeftStickUp, Button::LeftStickDown)),
+            ABS_RX => Some(Axis::Stick(Button::RightStickLeft, Button::RightStickRight)),
+            ABS_RY => Some(Axis::Stick(Button::RightStickUp, Button::RightStickDown)),
+            ABS_HAT0X => Some(Axis::Stick(Button::DPadLeft, Button::DPadRight)),
+            ABS_HAT0Y => Some(Axis::Stick(Button::DPadUp, Button::DPadDown)),
+            ABS_Z => Some(Axis::Trigger(Button::LeftTrigger)),
+            ABS_RZ => Some(Axis::Trigger(Button::RightTrigger)),
+            _ => None,
+        }
+    }
+
+    /// Turns one device's raw evdev events into button changes.
+    #[derive(Debug, Default)]
+    pub(super) struct EventDecoder {
+        ranges: HashMap<u16, (i32, i32)>,
+        held: Vec<Button>,
+    }
+
+    impl EventDecoder {
+        pub(super) fn set_range(&mut self, code: u16, min: i32, max: i32) {
+            if min < max {
+                self.ranges.insert(code, (min, max));
+            }
+        }
+
+        pub(super) fn decode(
+            &mut self,
+            event_type: u16,
+            code: u16,
+            value: i32,
+            events: &mut Vec<ButtonEvent>,
+        ) {
+            match event_type {
+                // A value of 2 is a key repeat, which still holds the button
+                EV_KEY => {
+                    if let Some((_, button)) = BUTTONS.iter().find(|(c, _)| *c == code) {
+                        self.set(*button, value != 0, events);
+                    }
+                }
+                EV_ABS => {
+                    let (min, max) = self.range(code);
+                    let half = (max - min) as f32 / 2.;
+                    match axis(code) {
+                        Some(Axis::Stick(negative, positive)) => {
+                            let position = (value - min) as f32 / half - 1.;
+                            self.set(negative, position < -0.5, events);
+                            self.set(positive, position > 0.5, events);
+                        }
+                        Some(Axis::Trigger(button)) => {
+                            self.set(button, (value - min) as f32 > half, events);
+                        }
+                        None => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        /// Releases every held button, as when the device is unplugged.
+        pub(super) fn release_all(&mut self, events: &mut Vec<ButtonEvent>) {
+            events.extend(self.held.drain(..).map(|button| ButtonEvent {
+                button,
+                pressed: false,
+            }));
+        }
+
+        fn range(&self, code: u16) -> (i32, i32) {
+            match self.ranges.get(&code) {
+                Some(range) => *range,
+                None => match code {
+                    ABS_HAT0X | ABS_HAT0Y => (-1, 1),
+                    ABS_Z | ABS_RZ => (0, 255),
+                    _ => (i16::MIN as i32, i16::MAX as i32),
+                },
+            }
+        }
+
+        fn set(&mut self, button: Button, pressed: bool, events: &mut Vec<ButtonEvent>) {
+            if self.held.contains(&button) == pressed {
+                return;
+            }
+            match pressed {
+                true => self.held.push(button),
+                false => self.held.retain(|held| *held != button),
+            }
+            events.push(ButtonEvent { button, pressed });
+        }
+    }
+
+    struct Device {
+        file: File,
+        name: String,
+        decoder: EventDecoder,
+    }
+
+    /// Reads every gamepad or joystick under `/dev/input` through the Linux
+    /// evdev interface. Devices that can't be opened, usually for lack of
+    /// permission, are skipped.
+    pub struct Evdev {
+        devices: Vec<Device>,
+    }
+
+    impl Evdev {
+        pub fn open() -> Self {
+            let mut paths: Vec<_> = fs::read_dir(INPUT_DIR)
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.file_name()
+                        .and_then(|name| name.to_str())
+                        .is_some_and(|name| name.starts_with(EVENT_PREFIX))
+                })
+                .collect();
+            paths.sort();
+            Self {
+                devices: paths
+                    .iter()
+                    .filter_map(|path| open_device(path).ok().flatten())
+                    .collect(),
+            }
+        }
+
+        /// Names of the opened gamepads.
+        pub fn names(&self) -> impl Iterator<Item = &str> {
+            self.devices.iter().map(|device| device.name.as_str())
+        }
+    }
+
+    impl InputBackend for Evdev {
+        fn poll(&mut self, events: &mut Vec<ButtonEvent>) -> io::Result<()> {
+            self.devices
+                .retain_mut(|device| match read_events(device, events) {
+                    Ok(()) => true,
+                    Err(_) => {
+                        device.decoder.release_all(events);
+                        false
+                    }
+                });
+            Ok(())
+        }
+    }
+
+    fn read_events(device: &mut Device, events: &mut Vec<ButtonEvent>) -> io::Result<()> {
+        let mut buffer = [0; EVENT_SIZE * EVENTS_PER_READ];
+        loop {
+            let read = match device.file.read(&mut buffer) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            for event in buffer[..read].chunks_exact(EVENT_SIZE) {
+                let field = &event[EVENT_TIME_SIZE..];
+                device.decoder.decode(
+                    u16::from_ne_bytes([field[0], field[1]]),
+                    u16::from_ne_bytes([field[2], field[3]]),
+                    i32::from_ne_bytes([field[4], field[5], field[6], field[7]]),
+                    events,
+                );
+            }
+        }
+    }
+
+    fn ioc_read(number: libc::c_ulong, size: usize) -> libc::c_ulong {
+        IOC_READ << 30 | (size as libc::c_ulong) << 16 | (b'E' as libc::c_ulong) << 8 | number
+    }
+
+    /// Opens an event device, or returns `None` if it isn't a gamepad.
+    fn open_device(path: &Path) -> io::Result<Option<Device>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+        let fd = file.as_raw_fd();
+
+        let mut key_bits = [0u8; KEY_MAX / 8 + 1];
+        let request = ioc_read(EVIOCGBIT + EV_KEY as libc::c_ulong, key_bits.len());
+        // SAFETY: the kernel writes at most key_bits.len() bytes
+        if unsafe { libc::ioctl(fd, request as _, key_bits.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let has_key = |code: usize| key_bits[code / 8] & 1 << (code % 8) != 0;
+        if !has_key(BTN_GAMEPAD) && !has_key(BTN_JOYSTICK) {
+            return Ok(None);
+        }
+
+        let mut name = [0u8; NAME_LEN];
+        // SAFETY: the kernel writes at most NAME_LEN bytes
+        unsafe { libc::ioctl(fd, ioc_read(EVIOCGNAME, NAME_LEN) as _, name.as_mut_ptr()) };
+        let name_len = name.iter().position(|byte| *byte == 0).unwrap_or(NAME_LEN);
+        let name = String::from_utf8_lossy(&name[..name_len]).into_owned();
+
+        let mut decoder = EventDecoder::default();
+        for code in AXES.iter() {
+            // SAFETY: input_absinfo is plain integers, and the kernel fills
+            // exactly one of them
+            let mut info: libc::input_absinfo = unsafe { std::mem::zeroed() };
+            let size = std::mem::size_of::<libc::input_absinfo>();
+            let request = ioc_read(EVIOCGABS + *code as libc::c_ulong, size);
+            if unsafe { libc::ioctl(fd, request as _, &mut info) } >= 0 {
+                decoder.set_range(*code, info.minimum, info.maximum);
+            }
+        }
+
+        Ok(Some(Device {
+            file,
+            name,
+            decoder,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Cpu;
+    use crate::quirks::Quirks;
+
+    #[test]
+    fn held_buttons_press_their_bound_keys() {
+        let mut gamepad = Gamepad::new(Mock::default());
+        gamepad.backend_mut().press(Button::DPadLeft);
+        gamepad.backend_mut().press(Button::South);
+        gamepad.poll().unwrap();
+
+        let mut keys = gamepad.keys(&Keymap::default());
+        keys.sort_unstable();
+        assert_eq!(keys, vec![0x4, 0x5]);
+
+        gamepad.backend_mut().release(Button::DPadLeft);
+        gamepad.poll().unwrap();
+        assert_eq!(gamepad.keys(&Keymap::default()), vec![0x5]);
+    }
+
+    #[test]
+    fn buttons_held_on_two_pads_need_two_releases() {
+        let mut gamepad = Gamepad::new(Mock::default());
+        gamepad.backend_mut().press(Button::Start);
+        gamepad.backend_mut().press(Button::Start);
+        gamepad.backend_mut().release(Button::Start);
+        gamepad.poll().unwrap();
+        assert!(gamepad.is_held(Button::Start));
+
+        gamepad.backend_mut().release(Button::Start);
+        gamepad.poll().unwrap();
+        assert!(!gamepad.is_held(Button::Start));
+    }
+
+    #[test]
+    fn gamepad_keys_reach_the_cpu() {
+        // 0x200: SKP V0; 0x202: JP 0x200; 0x204: JP 0x204
+        let rom = [0xE0, 0x9E, 0x12, 0x00, 0x12, 0x04];
+        let mut cpu = Cpu::from_bytes(&rom, Quirks::default()).unwrap();
+        let mut keymap = Keymap::default();
+        keymap.bind(0x0, &["PadEast".to_string()]);
+
+        let mut gamepad = Gamepad::new(Mock::default());
+        gamepad.backend_mut().press(Button::East);
+        gamepad.poll().unwrap();
+        cpu.set_keys(gamepad.keys(&keymap));
+        cpu.run_frame(2).unwrap();
+        assert_eq!(cpu.program_counter(), 0x204);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn evdev_axes_press_buttons_past_halfway() {
+        let mut decoder = evdev::EventDecoder::default();
+        decoder.set_range(0x00, 0, 255);
+        let mut events = Vec::new();
+
+        decoder.decode(0x03, 0x00, 10, &mut events);
+        decoder.decode(0x03, 0x00, 128, &mut events);
+        decoder.decode(0x03, 0x11, 1, &mut events);
+        decoder.decode(0x03, 0x05, 200, &mut events);
+        decoder.decode(0x01, 0x130, 1, &mut events);
+        decoder.decode(0x01, 0x130, 2, &mut events);
+        let pressed = |button| ButtonEvent {
+            button,
+            pressed: true,
+        };
+        let released = |button| ButtonEvent {
+            button,
+            pressed: false,
+        };
+        assert_eq!(
+            events,
+            vec![
+                pressed(Button::LeftStickLeft),
+                released(Button::LeftStickLeft),
+                pressed(Button::DPadDown),
+                pressed(Button::RightTrigger),
+                pressed(Button::South),
+            ]
+        );
+
+        events.clear();
+        decoder.release_all(&mut events);
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().all(|event| !event.pressed));
+    }
+}
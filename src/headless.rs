@@ -0,0 +1,60 @@
+use crate::audio::AudioSource;
+use crate::chip8::{Cpu, CpuError};
+
+/// Characters used to print a pixel, indexed by its plane mask.
+const PIXEL_CHARS: [char; 4] = ['.', '#', '+', '@'];
+
+/// Runs up to `cycles` instructions without a window, ticking the timers once
+/// every `cycles_per_frame` instructions as a host would at 60Hz. Stops early
+/// if the program halts and returns the number of instructions run.
+pub fn run(cpu: &mut Cpu, cycles: u64, cycles_per_frame: u32) -> Result<u64, CpuError> {
+    run_frames(cpu, cycles, cycles_per_frame, |_| {})
+}
+
+/// Like [`run`], but also renders the buzzer through `source`, one frame of
+/// samples per frame run.
+pub fn render_audio(
+    cpu: &mut Cpu,
+    cycles: u64,
+    cycles_per_frame: u32,
+    source: &mut AudioSource,
+) -> Result<Vec<f32>, CpuError> {
+    let mut samples = Vec::new();
+    run_frames(cpu, cycles, cycles_per_frame, |cpu| {
+        source.update(cpu);
+        source.render_frame(&mut samples);
+    })?;
+    Ok(samples)
+}
+
+/// Runs frames, calling `on_frame` before each frame's timer tick.
+fn run_frames(
+    cpu: &mut Cpu,
+    cycles: u64,
+    cycles_per_frame: u32,
+    mut on_frame: impl FnMut(&Cpu),
+) -> Result<u64, CpuError> {
+    let mut executed = 0;
+    while executed < cycles && !cpu.halted() {
+        let frame = (cycles - executed).min(cycles_per_frame as u64) as u32;
+        for _ in 0..frame {
+            cpu.cycle()?;
+        }
+        on_frame(cpu);
+        cpu.tick_timers();
+        executed += frame as u64;
+    }
+    Ok(executed)
+}
+
+/// Renders the display as text, one line per row, with `.` for unlit pixels
+/// and `#`, `+` or `@` for pixels lit in the first, second or both planes.
+pub fn screen_text(cpu: &Cpu) -> String {
+    let width = cpu.display_width();
+    let mut text = String::with_capacity((width + 1) * cpu.display_height());
+    for row in cpu.display().chunks(width) {
+        text.extend(row.iter().map(|pixel| PIXEL_CHARS[*pixel as usize & 0b11]));
+        text.push('\n');
+    }
+    text
+}
@@ -0,0 +1,519 @@
+use crate::chip8::Opcode;
+use std::fmt;
+
+/// A decoded CHIP-8, SUPER-CHIP or XO-CHIP instruction.
+///
+/// Every variant keeps all of the bits of its opcode, so an instruction can
+/// always be encoded back into the exact opcode it was decoded from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// 00CN
+    ScrollDown(u8),
+    /// 00E0
+    ClearScreen,
+    /// 00EE
+    Return,
+    /// 00FB
+    ScrollRight,
+    /// 00FC
+    ScrollLeft,
+    /// 00FD
+    Exit,
+    /// 00FE
+    LowRes,
+    /// 00FF
+    HighRes,
+    /// 1NNN
+    Jump(u16),
+    /// 2NNN
+    Call(u16),
+    /// 3XNN
+    SkipIfEqualImmediate(usize, u8),
+    /// 4XNN
+    SkipIfNotEqualImmediate(usize, u8),
+    /// 5XY0
+    SkipIfEqual(usize, usize),
+    /// 5XY2
+    SaveRange(usize, usize),
+    /// 5XY3
+    LoadRange(usize, usize),
+    /// 6XNN
+    LoadImmediate(usize, u8),
+    /// 7XNN
+    AddImmediate(usize, u8),
+    /// 8XY0
+    Move(usize, usize),
+    /// 8XY1
+    Or(usize, usize),
+    /// 8XY2
+    And(usize, usize),
+    /// 8XY3
+    Xor(usize, usize),
+    /// 8XY4
+    Add(usize, usize),
+    /// 8XY5
+    Subtract(usize, usize),
+    /// 8XY6
+    ShiftRight(usize, usize),
+    /// 8XY7
+    SubtractReverse(usize, usize),
+    /// 8XYE
+    ShiftLeft(usize, usize),
+    /// 9XY0
+    SkipIfNotEqual(usize, usize),
+    /// ANNN
+    LoadI(u16),
+    /// BNNN
+    JumpOffset(u16),
+    /// CXNN
+    Random(usize, u8),
+    /// DXYN
+    Draw(usize, usize, u8),
+    /// EX9E
+    SkipIfKey(usize),
+    /// EXA1
+    SkipIfNotKey(usize),
+    /// F000 NNNN, the address is the word following the opcode
+    LongLoadI,
+    /// FN01
+    SelectPlanes(u8),
+    /// F002
+    LoadAudio,
+    /// FX07
+    LoadDelay(usize),
+    /// FX0A
+    WaitKey(usize),
+    /// FX15
+    SetDelay(usize),
+    /// FX18
+    SetSound(usize),
+    /// FX1E
+    AddI(usize),
+    /// FX29
+    LoadFont(usize),
+    /// FX30
+    LoadBigFont(usize),
+    /// FX33
+    Bcd(usize),
+    /// FX3A
+    SetPitch(usize),
+    /// FX55
+    Store(usize),
+    /// FX65
+    Load(usize),
+    /// FX75
+    StoreFlags(usize),
+    /// FX85
+    LoadFlags(usize),
+    Unknown(Opcode),
+}
+
+pub fn decode(opcode: Opcode) -> Instruction {
+    let op_1 = (opcode & 0xF000) >> 12;
+    let op_2 = (opcode & 0x0F00) >> 8;
+    let op_3 = (opcode & 0x00F0) >> 4;
+    let op_4 = opcode & 0x000F;
+
+    let x = ((opcode & 0x0F00) >> 8) as usize;
+    let y = ((opcode & 0x00F0) >> 4) as usize;
+    let nnn = opcode & 0x0FFF;
+    let nn = (opcode & 0x00FF) as u8;
+    let n = (opcode & 0x000F) as u8;
+
+    match (op_1, op_2, op_3, op_4) {
+        (0, 0, 0xC, _) => Instruction::ScrollDown(n),
+        (0, 0, 0xE, 0) => Instruction::ClearScreen,
+        (0, 0, 0xE, 0xE) => Instruction::Return,
+        (0, 0, 0xF, 0xB) => Instruction::ScrollRight,
+        (0, 0, 0xF, 0xC) => Instruction::ScrollLeft,
+        (0, 0, 0xF, 0xD) => Instruction::Exit,
+        (0, 0, 0xF, 0xE) => Instruction::LowRes,
+        (0, 0, 0xF, 0xF) => Instruction::HighRes,
+        (1, _, _, _) => Instruction::Jump(nnn),
+        (2, _, _, _) => Instruction::Call(nnn),
+        (3, _, _, _) => Instruction::SkipIfEqualImmediate(x, nn),
+        (4, _, _, _) => Instruction::SkipIfNotEqualImmediate(x, nn),
+        (5, _, _, 0) => Instruction::SkipIfEqual(x, y),
+        (5, _, _, 2) => Instruction::SaveRange(x, y),
+        (5, _, _, 3) => Instruction::LoadRange(x, y),
+        (6, _, _, _) => Instruction::LoadImmediate(x, nn),
+        (7, _, _, _) => Instruction::AddImmediate(x, nn),
+        (8, _, _, 0) => Instruction::Move(x, y),
+        (8, _, _, 1) => Instruction::Or(x, y),
+        (8, _, _, 2) => Instruction::And(x, y),
+        (8, _, _, 3) => Instruction::Xor(x, y),
+        (8, _, _, 4) => Instruction::Add(x, y),
+        (8, _, _, 5) => Instruction::Subtract(x, y),
+        (8, _, _, 6) => Instruction::ShiftRight(x, y),
+        (8, _, _, 7) => Instruction::SubtractReverse(x, y),
+        (8, _, _, 0xE) => Instruction::ShiftLeft(x, y),
+        (9, _, _, 0) => Instruction::SkipIfNotEqual(x, y),
+        (0xA, _, _, _) => Instruction::LoadI(nnn),
+        (0xB, _, _, _) => Instruction::JumpOffset(nnn),
+        (0xC, _, _, _) => Instruction::Random(x, nn),
+        (0xD, _, _, _) => Instruction::Draw(x, y, n),
+        (0xE, _, 9, 0xE) => Instruction::SkipIfKey(x),
+        (0xE, _, 0xA, 1) => Instruction::SkipIfNotKey(x),
+        (0xF, 0, 0, 0) => Instruction::LongLoadI,
+        (0xF, _, 0, 1) => Instruction::SelectPlanes(x as u8),
+        (0xF, 0, 0, 2) => Instruction::LoadAudio,
+        (0xF, _, 0, 7) => Instruction::LoadDelay(x),
+        (0xF, _, 0, 0xA) => Instruction::WaitKey(x),
+        (0xF, _, 1, 5) => Instruction::SetDelay(x),
+        (0xF, _, 1, 8) => Instruction::SetSound(x),
+        (0xF, _, 1, 0xE) => Instruction::AddI(x),
+        (0xF, _, 2, 9) => Instruction::LoadFont(x),
+        (0xF, _, 3, 0) => Instruction::LoadBigFont(x),
+        (0xF, _, 3, 3) => Instruction::Bcd(x),
+        (0xF, _, 3, 0xA) => Instruction::SetPitch(x),
+        (0xF, _, 5, 5) => Instruction::Store(x),
+        (0xF, _, 6, 5) => Instruction::Load(x),
+        (0xF, _, 7, 5) => Instruction::StoreFlags(x),
+        (0xF, _, 8, 5) => Instruction::LoadFlags(x),
+        _ => Instruction::Unknown(opcode),
+    }
+}
+
+/// Mnemonic style used when formatting instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// Assembly mnemonics from Cowgod's technical reference, e.g. `LD V0, 0x12`.
+    Cowgod,
+    /// Octo statements, e.g. `v0 := 0x12`.
+    Octo,
+}
+
+impl Instruction {
+    /// Size in bytes, including the address word that follows F000.
+    pub fn size(&self) -> usize {
+        match self {
+            Instruction::LongLoadI => 4,
+            _ => 2,
+        }
+    }
+
+    /// Address operand of instructions that refer to memory.
+    pub fn address(&self) -> Option<u16> {
+        match self {
+            Instruction::Jump(address)
+            | Instruction::Call(address)
+            | Instruction::LoadI(address)
+            | Instruction::JumpOffset(address) => Some(*address),
+            _ => None,
+        }
+    }
+
+    /// Whether the instruction conditionally skips the one after it.
+    pub fn is_skip(&self) -> bool {
+        matches!(
+            self,
+            Instruction::SkipIfEqualImmediate(..)
+                | Instruction::SkipIfNotEqualImmediate(..)
+                | Instruction::SkipIfEqual(..)
+                | Instruction::SkipIfNotEqual(..)
+                | Instruction::SkipIfKey(..)
+                | Instruction::SkipIfNotKey(..)
+        )
+    }
+
+    pub fn display(&self, syntax: Syntax) -> Formatted<'_> {
+        Formatted {
+            instruction: self,
+            syntax,
+            operand: None,
+        }
+    }
+}
+
+/// An [`Instruction`] formatted in a particular [`Syntax`].
+pub struct Formatted<'a> {
+    instruction: &'a Instruction,
+    syntax: Syntax,
+    operand: Option<&'a str>,
+}
+
+impl<'a> Formatted<'a> {
+    /// Prints `operand`, typically a label, in place of the address operand.
+    /// For F000 it supplies the address word that follows the opcode.
+    pub fn with_operand(mut self, operand: &'a str) -> Self {
+        self.operand = Some(operand);
+        self
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.display(Syntax::Cowgod).fmt(f)
+    }
+}
+
+impl fmt::Display for Formatted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let address = match (self.operand, self.instruction.address()) {
+            (Some(operand), _) => operand.to_string(),
+            (None, Some(address)) => format!("0x{:03X}", address),
+            (None, None) => String::new(),
+        };
+        match self.syntax {
+            Syntax::Cowgod => fmt_cowgod(self.instruction, &address, self.operand, f),
+            Syntax::Octo => fmt_octo(self.instruction, &address, self.operand, f),
+        }
+    }
+}
+
+fn fmt_cowgod(
+    instruction: &Instruction,
+    address: &str,
+    operand: Option<&str>,
+    f: &mut fmt::Formatter,
+) -> fmt::Result {
+    match *instruction {
+        Instruction::ScrollDown(n) => write!(f, "SCD {}", n),
+        Instruction::ClearScreen => write!(f, "CLS"),
+        Instruction::Return => write!(f, "RET"),
+        Instruction::ScrollRight => write!(f, "SCR"),
+        Instruction::ScrollLeft => write!(f, "SCL"),
+        Instruction::Exit => write!(f, "EXIT"),
+        Instruction::LowRes => write!(f, "LOW"),
+        Instruction::HighRes => write!(f, "HIGH"),
+        Instruction::Jump(_) => write!(f, "JP {}", address),
+        Instruction::Call(_) => write!(f, "CALL {}", address),
+        Instruction::SkipIfEqualImmediate(x, nn) => write!(f, "SE V{:X}, 0x{:02X}", x, nn),
+        Instruction::SkipIfNotEqualImmediate(x, nn) => write!(f, "SNE V{:X}, 0x{:02X}", x, nn),
+        Instruction::SkipIfEqual(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+        Instruction::SaveRange(x, y) => write!(f, "SAVE V{:X}, V{:X}", x, y),
+        Instruction::LoadRange(x, y) => write!(f, "LOAD V{:X}, V{:X}", x, y),
+        Instruction::LoadImmediate(x, nn) => write!(f, "LD V{:X}, 0x{:02X}", x, nn),
+        Instruction::AddImmediate(x, nn) => write!(f, "ADD V{:X}, 0x{:02X}", x, nn),
+        Instruction::Move(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+        Instruction::Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+        Instruction::And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+        Instruction::Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+        Instruction::Add(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+        Instruction::Subtract(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+        Instruction::ShiftRight(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+        Instruction::SubtractReverse(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+        Instruction::ShiftLeft(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+        Instruction::SkipIfNotEqual(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+        Instruction::LoadI(_) => write!(f, "LD I, {}", address),
+        Instruction::JumpOffset(_) => write!(f, "JP V0, {}", address),
+        Instruction::Random(x, nn) => write!(f, "RND V{:X}, 0x{:02X}", x, nn),
+        Instruction::Draw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+        Instruction::SkipIfKey(x) => write!(f, "SKP V{:X}", x),
+        Instruction::SkipIfNotKey(x) => write!(f, "SKNP V{:X}", x),
+        Instruction::LongLoadI => match operand {
+            Some(operand) => write!(f, "LD I, LONG {}", operand),
+            None => write!(f, "LD I, LONG"),
+        },
+        Instruction::SelectPlanes(n) => write!(f, "PLANE {}", n),
+        Instruction::LoadAudio => write!(f, "AUDIO"),
+        Instruction::LoadDelay(x) => write!(f, "LD V{:X}, DT", x),
+        Instruction::WaitKey(x) => write!(f, "LD V{:X}, K", x),
+        Instruction::SetDelay(x) => write!(f, "LD DT, V{:X}", x),
+        Instruction::SetSound(x) => write!(f, "LD ST, V{:X}", x),
+        Instruction::AddI(x) => write!(f, "ADD I, V{:X}", x),
+        Instruction::LoadFont(x) => write!(f, "LD F, V{:X}", x),
+        Instruction::LoadBigFont(x) => write!(f, "LD HF, V{:X}", x),
+        Instruction::Bcd(x) => write!(f, "LD B, V{:X}", x),
+        Instruction::SetPitch(x) => write!(f, "PITCH V{:X}", x),
+        Instruction::Store(x) => write!(f, "LD [I], V{:X}", x),
+        Instruction::Load(x) => write!(f, "LD V{:X}, [I]", x),
+        Instruction::StoreFlags(x) => write!(f, "LD R, V{:X}", x),
+        Instruction::LoadFlags(x) => write!(f, "LD V{:X}, R", x),
+        Instruction::Unknown(opcode) => write!(f, "DW 0x{:04X}", opcode),
+    }
+}
+
+fn fmt_octo(
+    instruction: &Instruction,
+    address: &str,
+    operand: Option<&str>,
+    f: &mut fmt::Formatter,
+) -> fmt::Result {
+    match *instruction {
+        Instruction::ScrollDown(n) => write!(f, "scroll-down {}", n),
+        Instruction::ClearScreen => write!(f, "clear"),
+        Instruction::Return => write!(f, "return"),
+        Instruction::ScrollRight => write!(f, "scroll-right"),
+        Instruction::ScrollLeft => write!(f, "scroll-left"),
+        Instruction::Exit => write!(f, "exit"),
+        Instruction::LowRes => write!(f, "lores"),
+        Instruction::HighRes => write!(f, "hires"),
+        Instruction::Jump(_) => write!(f, "jump {}", address),
+        Instruction::Call(_) => write!(f, ":call {}", address),
+        // Octo expresses skips as the condition under which the next instruction runs
+        Instruction::SkipIfEqualImmediate(x, nn) => write!(f, "if v{:x} != 0x{:02X} then", x, nn),
+        Instruction::SkipIfNotEqualImmediate(x, nn) => {
+            write!(f, "if v{:x} == 0x{:02X} then", x, nn)
+        }
+        Instruction::SkipIfEqual(x, y) => write!(f, "if v{:x} != v{:x} then", x, y),
+        Instruction::SaveRange(x, y) => write!(f, "save v{:x} - v{:x}", x, y),
+        Instruction::LoadRange(x, y) => write!(f, "load v{:x} - v{:x}", x, y),
+        Instruction::LoadImmediate(x, nn) => write!(f, "v{:x} := 0x{:02X}", x, nn),
+        Instruction::AddImmediate(x, nn) => write!(f, "v{:x} += 0x{:02X}", x, nn),
+        Instruction::Move(x, y) => write!(f, "v{:x} := v{:x}", x, y),
+        Instruction::Or(x, y) => write!(f, "v{:x} |= v{:x}", x, y),
+        Instruction::And(x, y) => write!(f, "v{:x} &= v{:x}", x, y),
+        Instruction::Xor(x, y) => write!(f, "v{:x} ^= v{:x}", x, y),
+        Instruction::Add(x, y) => write!(f, "v{:x} += v{:x}", x, y),
+        Instruction::Subtract(x, y) => write!(f, "v{:x} -= v{:x}", x, y),
+        Instruction::ShiftRight(x, y) => write!(f, "v{:x} >>= v{:x}", x, y),
+        Instruction::SubtractReverse(x, y) => write!(f, "v{:x} =- v{:x}", x, y),
+        Instruction::ShiftLeft(x, y) => write!(f, "v{:x} <<= v{:x}", x, y),
+        Instruction::SkipIfNotEqual(x, y) => write!(f, "if v{:x} == v{:x} then", x, y),
+        Instruction::LoadI(_) => write!(f, "i := {}", address),
+        Instruction::JumpOffset(_) => write!(f, "jump0 {}", address),
+        Instruction::Random(x, nn) => write!(f, "v{:x} := random 0x{:02X}", x, nn),
+        Instruction::Draw(x, y, n) => write!(f, "sprite v{:x} v{:x} {}", x, y, n),
+        Instruction::SkipIfKey(x) => write!(f, "if v{:x} -key then", x),
+        Instruction::SkipIfNotKey(x) => write!(f, "if v{:x} key then", x),
+        Instruction::LongLoadI => match operand {
+            Some(operand) => write!(f, "i := long {}", operand),
+            None => write!(f, "i := long"),
+        },
+        Instruction::SelectPlanes(n) => write!(f, "plane {}", n),
+        Instruction::LoadAudio => write!(f, "audio"),
+        Instruction::LoadDelay(x) => write!(f, "v{:x} := delay", x),
+        Instruction::WaitKey(x) => write!(f, "v{:x} := key", x),
+        Instruction::SetDelay(x) => write!(f, "delay := v{:x}", x),
+        Instruction::SetSound(x) => write!(f, "buzzer := v{:x}", x),
+        Instruction::AddI(x) => write!(f, "i += v{:x}", x),
+        Instruction::LoadFont(x) => write!(f, "i := hex v{:x}", x),
+        Instruction::LoadBigFont(x) => write!(f, "i := bighex v{:x}", x),
+        Instruction::Bcd(x) => write!(f, "bcd v{:x}", x),
+        Instruction::SetPitch(x) => write!(f, "pitch := v{:x}", x),
+        Instruction::Store(x) => write!(f, "save v{:x}", x),
+        Instruction::Load(x) => write!(f, "load v{:x}", x),
+        Instruction::StoreFlags(x) => write!(f, "saveflags v{:x}", x),
+        Instruction::LoadFlags(x) => write!(f, "loadflags v{:x}", x),
+        Instruction::Unknown(opcode) => write!(f, "0x{:02X} 0x{:02X}", opcode >> 8, opcode & 0xFF),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_and_formats_in_both_syntaxes() {
+        let cases = [
+            (0x00C4, Instruction::ScrollDown(4), "SCD 4", "scroll-down 4"),
+            (0x00E0, Instruction::ClearScreen, "CLS", "clear"),
+            (0x00EE, Instruction::Return, "RET", "return"),
+            (0x00FF, Instruction::HighRes, "HIGH", "hires"),
+            (0x1234, Instruction::Jump(0x234), "JP 0x234", "jump 0x234"),
+            (
+                0x2ABC,
+                Instruction::Call(0xABC),
+                "CALL 0xABC",
+                ":call 0xABC",
+            ),
+            (
+                0x3A12,
+                Instruction::SkipIfEqualImmediate(0xA, 0x12),
+                "SE VA, 0x12",
+                "if va != 0x12 then",
+            ),
+            (
+                0x5123,
+                Instruction::LoadRange(1, 2),
+                "LOAD V1, V2",
+                "load v1 - v2",
+            ),
+            (
+                0x8236,
+                Instruction::ShiftRight(2, 3),
+                "SHR V2, V3",
+                "v2 >>= v3",
+            ),
+            (
+                0x8237,
+                Instruction::SubtractReverse(2, 3),
+                "SUBN V2, V3",
+                "v2 =- v3",
+            ),
+            (
+                0xA300,
+                Instruction::LoadI(0x300),
+                "LD I, 0x300",
+                "i := 0x300",
+            ),
+            (
+                0xB200,
+                Instruction::JumpOffset(0x200),
+                "JP V0, 0x200",
+                "jump0 0x200",
+            ),
+            (
+                0xD125,
+                Instruction::Draw(1, 2, 5),
+                "DRW V1, V2, 5",
+                "sprite v1 v2 5",
+            ),
+            (
+                0xE39E,
+                Instruction::SkipIfKey(3),
+                "SKP V3",
+                "if v3 -key then",
+            ),
+            (0xF000, Instruction::LongLoadI, "LD I, LONG", "i := long"),
+            (0xF201, Instruction::SelectPlanes(2), "PLANE 2", "plane 2"),
+            (0xF40A, Instruction::WaitKey(4), "LD V4, K", "v4 := key"),
+            (0xF533, Instruction::Bcd(5), "LD B, V5", "bcd v5"),
+            (0xF655, Instruction::Store(6), "LD [I], V6", "save v6"),
+            (
+                0xF785,
+                Instruction::LoadFlags(7),
+                "LD V7, R",
+                "loadflags v7",
+            ),
+            (
+                0x5121,
+                Instruction::Unknown(0x5121),
+                "DW 0x5121",
+                "0x51 0x21",
+            ),
+            (
+                0xE1FF,
+                Instruction::Unknown(0xE1FF),
+                "DW 0xE1FF",
+                "0xE1 0xFF",
+            ),
+        ];
+        for (opcode, instruction, cowgod, octo) in cases {
+            assert_eq!(decode(opcode), instruction, "{:04X}", opcode);
+            assert_eq!(instruction.display(Syntax::Cowgod).to_string(), cowgod);
+            assert_eq!(instruction.display(Syntax::Octo).to_string(), octo);
+            assert_eq!(instruction.to_string(), cowgod);
+        }
+    }
+
+    #[test]
+    fn operands_replace_addresses() {
+        let jump = Instruction::Jump(0x234);
+        assert_eq!(
+            jump.display(Syntax::Cowgod)
+                .with_operand("loop")
+                .to_string(),
+            "JP loop"
+        );
+        let long = Instruction::LongLoadI;
+        assert_eq!(
+            long.display(Syntax::Octo)
+                .with_operand("0x1234")
+                .to_string(),
+            "i := long 0x1234"
+        );
+    }
+
+    #[test]
+    fn sizes_addresses_and_skips() {
+        assert_eq!(decode(0xF000).size(), 4);
+        assert_eq!(decode(0x6000).size(), 2);
+        assert_eq!(decode(0x2ABC).address(), Some(0xABC));
+        assert_eq!(decode(0x6ABC).address(), None);
+        assert!(decode(0x9120).is_skip());
+        assert!(decode(0xE1A1).is_skip());
+        assert!(!decode(0x1200).is_skip());
+    }
+}
@@ -0,0 +1,350 @@
+use crate::chip8::KEY_COUNT;
+use std::collections::HashMap;
+use std::fmt;
+
+const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+const DEFAULT_SECTION: &str = "default";
+const ROM_SECTION_PREFIX: &str = "rom.";
+
+/// The COSMAC VIP keypad laid over the left side of a QWERTY keyboard, by row:
+///
+/// ```text
+/// 1 2 3 C      1 2 3 4
+/// 4 5 6 D      Q W E R
+/// 7 8 9 E  ->  A S D F
+/// A 0 B F      Z X C V
+/// ```
+const QWERTY: [(u8, &str); KEY_COUNT] = [
+    (0x1, "Key1"),
+    (0x2, "Key2"),
+    (0x3, "Key3"),
+    (0xC, "Key4"),
+    (0x4, "Q"),
+    (0x5, "W"),
+    (0x6, "E"),
+    (0xD, "R"),
+    (0x7, "A"),
+    (0x8, "S"),
+    (0x9, "D"),
+    (0xE, "F"),
+    (0xA, "Z"),
+    (0x0, "X"),
+    (0xB, "C"),
+    (0xF, "V"),
+];
+
+/// Gamepad directions on the keypad's 2, 4, 6 and 8 arrows, and the face
+/// buttons on the keys around them.
+const GAMEPAD: [(u8, &str); 12] = [
+    (0x2, "PadDPadUp"),
+    (0x8, "PadDPadDown"),
+    (0x4, "PadDPadLeft"),
+    (0x6, "PadDPadRight"),
+    (0x2, "PadLeftStickUp"),
+    (0x8, "PadLeftStickDown"),
+    (0x4, "PadLeftStickLeft"),
+    (0x6, "PadLeftStickRight"),
+    (0x5, "PadSouth"),
+    (0x0, "PadEast"),
+    (0x7, "PadWest"),
+    (0x9, "PadNorth"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    InvalidSection(String),
+    InvalidKey(String),
+    InvalidValue(String),
+    Syntax,
+}
+
+/// An error in a keymap config file, with the 1-based line it was found on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeymapError {
+    pub line: usize,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            ErrorKind::InvalidSection(section) => write!(
+                f,
+                "unknown section [{}], expected [{}] or [{}<rom hash>]",
+                section, DEFAULT_SECTION, ROM_SECTION_PREFIX
+            ),
+            ErrorKind::InvalidKey(key) => write!(f, "{} is not a hex key from 0 to F", key),
+            ErrorKind::InvalidValue(value) => {
+                write!(f, "{} is not a key name or list of key names", value)
+            }
+            ErrorKind::Syntax => write!(f, "expected [section] or key = value"),
+        }
+    }
+}
+
+impl std::error::Error for KeymapError {}
+
+/// Maps host key names, compared case insensitively, to CHIP-8 keys.
+///
+/// A single digit name like `1` is shorthand for `Key1`. Gamepad buttons are
+/// named by [`Button::name`](crate::gamepad::Button::name).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    keys: HashMap<String, u8>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let mut keymap = Self {
+            keys: HashMap::new(),
+        };
+        for (key, name) in QWERTY.iter().chain(GAMEPAD.iter()) {
+            keymap.keys.insert(normalize(name), *key);
+        }
+        keymap
+    }
+}
+
+impl Keymap {
+    /// The CHIP-8 key bound to a host key, if any.
+    pub fn key(&self, host_key: &str) -> Option<u8> {
+        self.keys.get(&normalize(host_key)).copied()
+    }
+
+    /// Replaces the bindings of `key` with `host_keys`. Keyboard and gamepad
+    /// bindings are replaced separately, so rebinding a key on the keyboard
+    /// keeps its gamepad buttons and vice versa. An empty list unbinds both.
+    pub fn bind(&mut self, key: u8, host_keys: &[String]) {
+        let keyboard = host_keys.is_empty() || host_keys.iter().any(|name| !is_pad(name));
+        let gamepad = host_keys.is_empty() || host_keys.iter().any(|name| is_pad(name));
+        self.keys.retain(|name, bound| match is_pad(name) {
+            true => *bound != key || !gamepad,
+            false => *bound != key || !keyboard,
+        });
+        for host_key in host_keys {
+            self.keys.insert(normalize(host_key), key);
+        }
+    }
+}
+
+fn is_pad(host_key: &str) -> bool {
+    host_key.to_ascii_lowercase().starts_with("pad")
+}
+
+fn normalize(host_key: &str) -> String {
+    match host_key.len() == 1 && host_key.as_bytes()[0].is_ascii_digit() {
+        true => format!("key{}", host_key),
+        false => host_key.to_ascii_lowercase(),
+    }
+}
+
+/// Bindings of CHIP-8 keys to one or more host keys.
+type Bindings = Vec<(u8, Vec<String>)>;
+
+/// A keymap config file with a global section and per-ROM overrides.
+///
+/// The file is a small subset of TOML. Each section binds hex keys to a host
+/// key name or a list of them, replacing those keys' default bindings:
+///
+/// ```toml
+/// [default]
+/// 5 = "Z"
+///
+/// # Breakout, identified by its rom_hash in hex
+/// [rom.2671acb470b32f3c]
+/// 4 = ["Left", "Q"]
+/// 6 = ["Right", "E"]
+/// ```
+///
+/// Key names are those of the host's keyboard library, such as `A`, `Key1`,
+/// `Up` or `NumPad5`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeymapConfig {
+    default: Bindings,
+    roms: HashMap<u64, Bindings>,
+}
+
+impl KeymapConfig {
+    pub fn parse(text: &str) -> Result<Self, KeymapError> {
+        let mut config = Self::default();
+        let mut section = &mut config.default;
+        for (index, line) in text.lines().enumerate() {
+            let error = |kind| KeymapError {
+                line: index + 1,
+                kind,
+            };
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line
+                .strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+            {
+                let name = name.trim();
+                section = match name {
+                    DEFAULT_SECTION => &mut config.default,
+                    _ => {
+                        let hash = name
+                            .strip_prefix(ROM_SECTION_PREFIX)
+                            .map(|hash| unquote(hash.trim()).unwrap_or(hash))
+                            .and_then(|hash| u64::from_str_radix(hash, 16).ok())
+                            .ok_or_else(|| error(ErrorKind::InvalidSection(name.to_string())))?;
+                        config.roms.entry(hash).or_default()
+                    }
+                };
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error(ErrorKind::Syntax))?;
+            let key = key.trim();
+            let key = u8::from_str_radix(unquote(key).unwrap_or(key), 16)
+                .ok()
+                .filter(|key| (*key as usize) < KEY_COUNT)
+                .ok_or_else(|| error(ErrorKind::InvalidKey(key.to_string())))?;
+            let value = value.trim();
+            let host_keys = parse_names(value)
+                .ok_or_else(|| error(ErrorKind::InvalidValue(value.to_string())))?;
+            section.push((key, host_keys));
+        }
+        Ok(config)
+    }
+
+    /// The default keymap with the config's global and per-ROM bindings applied.
+    pub fn keymap(&self, rom_hash: u64) -> Keymap {
+        let mut keymap = Keymap::default();
+        let overrides = self.roms.get(&rom_hash).into_iter().flatten();
+        for (key, host_keys) in self.default.iter().chain(overrides) {
+            keymap.bind(*key, host_keys);
+        }
+        keymap
+    }
+}
+
+/// Identifies a ROM for per-ROM settings, using the 64 bit FNV-1a hash.
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn unquote(text: &str) -> Option<&str> {
+    text.strip_prefix('"')?.strip_suffix('"')
+}
+
+/// Parses `"name"` or `["name", ...]`.
+fn parse_names(value: &str) -> Option<Vec<String>> {
+    let names = match value
+        .strip_prefix('[')
+        .and_then(|value| value.strip_suffix(']'))
+    {
+        Some(list) => list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(unquote)
+            .collect::<Option<Vec<_>>>()?,
+        None => vec![unquote(value)?],
+    };
+    Some(names.into_iter().map(str::to_string).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_qwerty() {
+        let keymap = Keymap::default();
+        assert_eq!(keymap.key("Key1"), Some(0x1));
+        assert_eq!(keymap.key("x"), Some(0x0));
+        assert_eq!(keymap.key("V"), Some(0xF));
+        assert_eq!(keymap.key("Up"), None);
+        assert_eq!(keymap.key("PadDPadUp"), Some(0x2));
+    }
+
+    #[test]
+    fn rom_sections_override_default_section() {
+        let config = KeymapConfig::parse(
+            "
+            # comment
+            [default]
+            5 = \"Z\"      # moves 5 off W
+            A = \"2\"
+
+            [rom.00000000000000ff]
+            \"5\" = [\"Up\", \"K\"]
+            ",
+        )
+        .unwrap();
+
+        let keymap = config.keymap(0);
+        assert_eq!(keymap.key("Z"), Some(0x5));
+        assert_eq!(keymap.key("W"), None);
+        assert_eq!(keymap.key("Key2"), Some(0xA));
+
+        let keymap = config.keymap(0xFF);
+        assert_eq!(keymap.key("Up"), Some(0x5));
+        assert_eq!(keymap.key("K"), Some(0x5));
+        assert_eq!(keymap.key("Z"), None);
+        assert_eq!(keymap.key("2"), Some(0xA));
+    }
+
+    #[test]
+    fn keyboard_and_gamepad_bindings_are_replaced_separately() {
+        let config = KeymapConfig::parse(
+            "
+            [default]
+            2 = \"Up\"
+            5 = \"PadStart\"
+            8 = []
+            ",
+        )
+        .unwrap();
+
+        let keymap = config.keymap(0);
+        assert_eq!(keymap.key("Up"), Some(0x2));
+        assert_eq!(keymap.key("Key2"), None);
+        assert_eq!(keymap.key("PadDPadUp"), Some(0x2));
+        assert_eq!(keymap.key("PadStart"), Some(0x5));
+        assert_eq!(keymap.key("PadSouth"), None);
+        assert_eq!(keymap.key("W"), Some(0x5));
+        assert_eq!(keymap.key("S"), None);
+        assert_eq!(keymap.key("PadDPadDown"), None);
+    }
+
+    #[test]
+    fn errors_report_their_line() {
+        let err = KeymapConfig::parse("[default]\n10 = \"A\"\n").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert_eq!(err.kind, ErrorKind::InvalidKey("10".to_string()));
+
+        let err = KeymapConfig::parse("[rom.xyz]").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidSection("rom.xyz".to_string()));
+
+        let err = KeymapConfig::parse("1 = A").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidValue("A".to_string()));
+    }
+
+    #[test]
+    fn rom_hash_is_fnv_1a() {
+        assert_eq!(rom_hash(b""), 0xCBF2_9CE4_8422_2325);
+        assert_eq!(rom_hash(b"a"), 0xAF63_DC4C_8601_EC8C);
+    }
+}
@@ -0,0 +1,29 @@
+//! Core of a CHIP-8, SUPER-CHIP and XO-CHIP interpreter.
+//!
+//! The core has no windowing or audio dependencies. A host drives it by
+//! calling [`Cpu::cycle`](chip8::Cpu::cycle), feeding it input through
+//! [`Cpu::set_keys`](chip8::Cpu::set_keys) and presenting the framebuffer and
+//! sound state it exposes. The `chip8-rs` binary, built with the default
+//! `frontend` feature, is one such host using minifb and rodio.
+
+pub mod assembler;
+pub mod audio;
+pub mod chip8;
+pub mod debugger;
+pub mod disassembler;
+pub mod gamepad;
+pub mod headless;
+pub mod instruction;
+pub mod keymap;
+pub mod octo;
+pub mod quirks;
+pub mod renderer;
+pub mod rewind;
+mod state;
+pub mod timing;
+
+pub use chip8::{Cpu, CpuError, LoadError};
+pub use instruction::{decode, Instruction, Syntax};
+pub use quirks::Quirks;
+pub use rewind::Rewind;
+pub use state::StateError;
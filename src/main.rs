@@ -1,113 +1,1046 @@
-use minifb::{Key, Window, WindowOptions};
-use rodio::{source::SineWave, OutputStream, Sink};
-use std::time::{Duration, Instant};
+use clap::{crate_name, crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
+use minifb::{Key, KeyRepeat, ScaleMode, Window, WindowOptions};
+use rodio::{OutputStream, Sink, Source};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-mod chip8;
+use chip8_rs::audio::{self, AudioSource, Tone};
+use chip8_rs::chip8::FRAMES_PER_SEC;
+use chip8_rs::debugger::{Command, Debugger, StopReason};
+use chip8_rs::gamepad::{Gamepad, InputBackend};
+use chip8_rs::keymap::{self, Keymap, KeymapConfig};
+use chip8_rs::renderer::{Blend, FrameBlender, Overlay, Palette, Renderer};
+use chip8_rs::timing::Timing;
+use chip8_rs::{
+    assembler, chip8, disassembler, headless, instruction, octo, quirks, Instruction, Rewind,
+};
 
-const WINDOW_WIDTH: usize = 640;
-const WINDOW_HEIGHT: usize = 320;
-const FRAMES_PER_SEC: f64 = 60.;
-const CYCLES_PER_SEC: f64 = 600.;
+const WINDOW_TITLE: &str = "Chip-8";
+const DEFAULT_SCALE: usize = 10;
+const MAX_SCALE: usize = 32;
+const DEFAULT_CPU_HZ: u32 = 600;
+/// Seed `test` uses unless one is given, so snapshots stay reproducible.
+const TEST_SEED: u64 = 0;
+const SUBCOMMANDS: [&str; 6] = ["run", "disasm", "asm", "octo", "info", "test"];
+const SAVE_SLOTS: u8 = 10;
+const OCTO_EXTENSION: &str = "8o";
+const CONFIG_DIR: &str = "chip8-rs";
+const KEYMAP_FILE: &str = "keymap.toml";
+const REWIND_FRAMES: usize = 30 * FRAMES_PER_SEC as usize;
+
+struct Args {
+    rom_location: String,
+    quirks: quirks::Quirks,
+    seed: Option<u64>,
+    cycles_per_frame: u32,
+    timing: Timing,
+    debug: bool,
+    headless: bool,
+    max_cycles: Option<u64>,
+    dump_screen: Option<String>,
+    keymap: Option<String>,
+    mute: bool,
+    tone: Tone,
+    volume: f32,
+    wav: Option<String>,
+    scale: usize,
+    palette: Palette,
+    overlay: Overlay,
+    persistence: f32,
+    blend: Blend,
+}
 
 fn main() {
-    let rom_location = std::env::args().nth(1).expect("Must specify rom location");
-    let mut cpu = create_cpu(&rom_location);
-    let (_stream, sink) = create_audio();
-    let mut window = create_window();
+    let cpu_hz_help = format!("Instructions run per second [default: {}]", DEFAULT_CPU_HZ);
+    let scale_help = format!(
+        "Initial window size as a multiple of the display [default: {}]",
+        DEFAULT_SCALE
+    );
+    let matches = cli(&cpu_hz_help, &scale_help).get_matches_from(cli_args());
+    match matches.subcommand() {
+        ("run", Some(matches)) => run(run_args(matches)),
+        ("disasm", Some(matches)) => disassemble(matches),
+        ("asm", Some(matches)) => assemble(matches, |source| {
+            assembler::assemble(source).map_err(|err| err.to_string())
+        }),
+        ("octo", Some(matches)) => assemble(matches, |source| {
+            octo::compile(source).map_err(|err| err.to_string())
+        }),
+        ("info", Some(matches)) => info(matches),
+        ("test", Some(matches)) => test_rom(matches),
+        _ => unreachable!("a subcommand is required"),
+    }
+}
 
-    let mut last_cycle_time = Instant::now();
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        update_keys(&window, &mut cpu);
-        last_cycle_time = update_cpu(last_cycle_time.elapsed(), &mut cpu);
-        update_audio(&cpu, &sink);
-        update_window(&cpu, &mut window);
+/// The command line, with `run` inserted when it starts with a rom instead of
+/// a subcommand.
+fn cli_args() -> Vec<OsString> {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let starts_with_rom = args.get(1).is_some_and(|arg| {
+        let arg = arg.to_string_lossy();
+        !arg.starts_with('-') && !SUBCOMMANDS.contains(&arg.as_ref())
+    });
+    if starts_with_rom {
+        args.insert(1, "run".into());
     }
+    args
+}
+
+fn cli<'a>(cpu_hz_help: &'a str, scale_help: &'a str) -> App<'a, 'a> {
+    App::new(crate_name!())
+        .version(crate_version!())
+        .about("CHIP-8, SUPER-CHIP and XO-CHIP emulator")
+        .settings(&[
+            AppSettings::SubcommandRequiredElseHelp,
+            AppSettings::VersionlessSubcommands,
+        ])
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Runs a rom, or Octo source, in a window")
+                .arg(rom_arg())
+                .arg(quirks_arg())
+                .arg(seed_arg())
+                .arg(cpu_hz_arg(cpu_hz_help))
+                .arg(
+                    Arg::with_name("timing")
+                        .long("timing")
+                        .value_name("MODEL")
+                        .help("flat, or vip to take as long as the COSMAC VIP")
+                        .validator(validate_parse::<Timing>)
+                        .conflicts_with_all(&["cpu-hz", "debug", "headless"]),
+                )
+                .arg(
+                    Arg::with_name("scale")
+                        .long("scale")
+                        .value_name("N")
+                        .help(scale_help)
+                        .validator(validate_scale),
+                )
+                .arg(
+                    Arg::with_name("palette")
+                        .long("palette")
+                        .value_name("NAME|COLORS")
+                        .help("Display colors, by name or as a list like 000000,33FF66")
+                        .validator(validate_parse::<Palette>),
+                )
+                .arg(
+                    Arg::with_name("overlay")
+                        .long("overlay")
+                        .value_name("NAME")
+                        .help("none, grid or scanlines")
+                        .validator(validate_parse::<Overlay>),
+                )
+                .arg(
+                    Arg::with_name("persistence")
+                        .long("persistence")
+                        .value_name("0-1")
+                        .help("How slowly pixels fade out")
+                        .validator(validate_fraction),
+                )
+                .arg(
+                    Arg::with_name("blend")
+                        .long("blend")
+                        .value_name("MODE")
+                        .help("off, draws or a number of frames to blend")
+                        .validator(validate_parse::<Blend>),
+                )
+                .arg(
+                    Arg::with_name("mute")
+                        .long("mute")
+                        .help("Doesn't play the buzzer"),
+                )
+                .arg(
+                    Arg::with_name("tone")
+                        .long("tone")
+                        .value_name("HZ")
+                        .help("Plays the buzzer as a square wave at this frequency")
+                        .validator(validate_frequency),
+                )
+                .arg(
+                    Arg::with_name("volume")
+                        .long("volume")
+                        .value_name("0-1")
+                        .help("Buzzer volume [default: 0.25]")
+                        .validator(validate_fraction),
+                )
+                .arg(
+                    Arg::with_name("keymap")
+                        .long("keymap")
+                        .value_name("PATH")
+                        .help("Keymap config to use instead of the default one"),
+                )
+                .arg(
+                    Arg::with_name("debug")
+                        .long("debug")
+                        .help("Starts paused and reads debugger commands from stdin")
+                        .conflicts_with("headless"),
+                )
+                .arg(
+                    Arg::with_name("headless")
+                        .long("headless")
+                        .help("Runs without a window or audio, then prints the screen")
+                        .requires("max-cycles"),
+                )
+                .arg(max_cycles_arg().requires("headless"))
+                .arg(
+                    Arg::with_name("dump-screen")
+                        .long("dump-screen")
+                        .value_name("PATH")
+                        .help("Writes the screen to a file instead of stdout")
+                        .requires("headless"),
+                )
+                .arg(
+                    Arg::with_name("wav")
+                        .long("wav")
+                        .value_name("PATH")
+                        .help("Records the buzzer to a WAV file")
+                        .requires("headless"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("Prints an assembly listing of a rom")
+                .arg(rom_arg())
+                .arg(
+                    Arg::with_name("syntax")
+                        .long("syntax")
+                        .value_name("SYNTAX")
+                        .possible_values(&["cowgod", "octo"])
+                        .default_value("cowgod")
+                        .help("Mnemonics to list instructions with"),
+                ),
+        )
+        .subcommand(assemble_subcommand("asm", "Assembles a listing into a rom"))
+        .subcommand(assemble_subcommand(
+            "octo",
+            "Compiles Octo source into a rom",
+        ))
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Prints a rom's size, hash and the platform it needs")
+                .arg(rom_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("test")
+                .about("Runs a rom headless and compares its screen with a snapshot")
+                .arg(rom_arg())
+                .arg(
+                    Arg::with_name("expect")
+                        .long("expect")
+                        .value_name("PATH")
+                        .help("Screen written by run --headless --dump-screen")
+                        .required(true),
+                )
+                .arg(max_cycles_arg().required(true))
+                .arg(quirks_arg())
+                .arg(seed_arg().help("Seed for CXNN's random numbers [default: 0]"))
+                .arg(cpu_hz_arg(cpu_hz_help))
+                .arg(
+                    Arg::with_name("update")
+                        .long("update")
+                        .help("Rewrites the snapshot with the screen instead"),
+                ),
+        )
+}
+
+fn rom_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("rom")
+        .value_name("ROM")
+        .help("Rom file, or Octo source ending in .8o")
+        .required(true)
+}
+
+fn quirks_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("quirks")
+        .long("quirks")
+        .value_name("PRESET")
+        .help("vip, chip48, schip or xochip [default: vip]")
+        .validator(validate_parse::<quirks::Quirks>)
+}
+
+fn seed_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("seed")
+        .long("seed")
+        .value_name("N")
+        .help("Seed for CXNN's random numbers")
+        .validator(validate_whole)
+}
+
+fn cpu_hz_arg<'a>(help: &'a str) -> Arg<'a, 'a> {
+    Arg::with_name("cpu-hz")
+        .long("cpu-hz")
+        .value_name("HZ")
+        .help(help)
+        .validator(validate_cpu_hz)
+}
+
+fn max_cycles_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("max-cycles")
+        .long("max-cycles")
+        .alias("cycles")
+        .value_name("N")
+        .help("Instructions to run before stopping")
+        .validator(validate_count)
+}
+
+fn assemble_subcommand<'a, 'b>(name: &str, about: &'b str) -> App<'a, 'b> {
+    SubCommand::with_name(name)
+        .about(about)
+        .arg(Arg::with_name("source").value_name("SOURCE").required(true))
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .value_name("ROM")
+                .help("Where to write the rom [default: SOURCE with a .ch8 extension]"),
+        )
 }
 
-fn create_cpu(rom_location: &str) -> chip8::Cpu {
-    let rom = std::fs::File::open(&rom_location).expect("Failed to open rom");
-    chip8::Cpu::new(rom)
+/// Accepts values `T` parses, with its error otherwise.
+fn validate_parse<T>(value: String) -> Result<(), String>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value
+        .parse::<T>()
+        .map(|_| ())
+        .map_err(|err| err.to_string())
 }
 
-fn update_cpu(time_since_last_process: Duration, cpu: &mut chip8::Cpu) -> Instant {
-    let cycle_count = (CYCLES_PER_SEC * time_since_last_process.as_secs_f64()).round() as u64;
-    let start_time = Instant::now();
-    for _ in 0..cycle_count {
-        cpu.cycle();
+fn validate_whole(value: String) -> Result<(), String> {
+    match value.parse::<u64>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err("must be a whole number".to_string()),
     }
-    start_time
 }
 
-fn update_keys(window: &Window, cpu: &mut chip8::Cpu) {
-    if let Some(keys) = window.get_keys() {
-        let key_values = keys
-            .into_iter()
-            .filter_map(|key| match key {
-                Key::Key1 => Some(1),
-                Key::Key2 => Some(2),
-                Key::Key3 => Some(3),
-                Key::Key4 => Some(0xC),
+fn validate_count(value: String) -> Result<(), String> {
+    match value.parse::<u64>() {
+        Ok(count) if count > 0 => Ok(()),
+        _ => Err("must be a whole number above 0".to_string()),
+    }
+}
 
-                Key::Q => Some(4),
-                Key::W => Some(5),
-                Key::E => Some(6),
-                Key::R => Some(0xD),
+fn validate_cpu_hz(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(hz) if hz >= FRAMES_PER_SEC => Ok(()),
+        _ => Err(format!(
+            "must be a whole number of instructions, at least {}",
+            FRAMES_PER_SEC
+        )),
+    }
+}
 
-                Key::A => Some(7),
-                Key::S => Some(8),
-                Key::D => Some(9),
-                Key::F => Some(0xE),
+fn validate_scale(value: String) -> Result<(), String> {
+    match value.parse::<usize>() {
+        Ok(scale) if (1..=MAX_SCALE).contains(&scale) => Ok(()),
+        _ => Err(format!("must be a whole number from 1 to {}", MAX_SCALE)),
+    }
+}
 
-                Key::Z => Some(0xA),
-                Key::X => Some(0),
-                Key::C => Some(0xB),
-                Key::V => Some(0xF),
+fn validate_fraction(value: String) -> Result<(), String> {
+    match value.parse::<f32>() {
+        Ok(fraction) if (0. ..=1.).contains(&fraction) => Ok(()),
+        _ => Err("must be a number from 0 to 1".to_string()),
+    }
+}
 
-                _ => None,
-            })
-            .collect();
-        cpu.set_keys(key_values);
+fn validate_frequency(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(hz) if hz > 0. => Ok(()),
+        _ => Err("must be a frequency in Hz above 0".to_string()),
     }
 }
 
-fn create_audio() -> (OutputStream, Sink) {
-    let (stream, handle) = OutputStream::try_default().unwrap();
-    let sink = Sink::try_new(&handle).unwrap();
-    let source = SineWave::new(512);
-    sink.pause();
-    sink.append(source);
-    (stream, sink)
+/// The value of an argument that has already been validated.
+fn parsed<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).and_then(|value| value.parse().ok())
+}
+
+/// Instructions run per frame at the `--cpu-hz` rate.
+fn cycles_per_frame(matches: &ArgMatches) -> u32 {
+    let cpu_hz = parsed(matches, "cpu-hz").unwrap_or(DEFAULT_CPU_HZ);
+    (cpu_hz as f64 / FRAMES_PER_SEC as f64).round() as u32
+}
+
+fn run_args(matches: &ArgMatches) -> Args {
+    Args {
+        rom_location: matches.value_of("rom").unwrap().to_string(),
+        quirks: parsed(matches, "quirks").unwrap_or_default(),
+        seed: parsed(matches, "seed"),
+        cycles_per_frame: cycles_per_frame(matches),
+        timing: parsed(matches, "timing").unwrap_or_default(),
+        debug: matches.is_present("debug"),
+        headless: matches.is_present("headless"),
+        max_cycles: parsed(matches, "max-cycles"),
+        dump_screen: matches.value_of("dump-screen").map(String::from),
+        keymap: matches.value_of("keymap").map(String::from),
+        mute: matches.is_present("mute"),
+        tone: parsed(matches, "tone").map_or(Tone::default(), Tone::Square),
+        volume: parsed(matches, "volume").unwrap_or(audio::DEFAULT_VOLUME),
+        wav: matches.value_of("wav").map(String::from),
+        scale: parsed(matches, "scale").unwrap_or(DEFAULT_SCALE),
+        palette: parsed(matches, "palette").unwrap_or_default(),
+        overlay: parsed(matches, "overlay").unwrap_or_default(),
+        persistence: parsed(matches, "persistence").unwrap_or(0.),
+        blend: parsed(matches, "blend").unwrap_or_default(),
+    }
+}
+
+/// Loads a rom into a new cpu and returns it with the rom's hash. The seed is
+/// printed when a random one is used. Exits if the rom can't be loaded.
+fn load_cpu(rom_location: &str, quirks: quirks::Quirks, seed: Option<u64>) -> (chip8::Cpu, u64) {
+    let loaded = read_rom(rom_location).and_then(|rom| {
+        let cpu = chip8::Cpu::from_bytes(&rom, quirks)?;
+        Ok((cpu, keymap::rom_hash(&rom)))
+    });
+    match loaded {
+        Ok((cpu, rom_hash)) => match seed {
+            Some(seed) => (cpu.with_seed(seed), rom_hash),
+            None => {
+                eprintln!("Using random seed {}", cpu.seed());
+                (cpu, rom_hash)
+            }
+        },
+        Err(err) => {
+            eprintln!("Failed to load {}: {}", rom_location, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run(args: Args) {
+    let rom_location = args.rom_location;
+    let (mut cpu, rom_hash) = load_cpu(&rom_location, args.quirks, args.seed);
+    let audio = AudioSource::new(audio::DEFAULT_SAMPLE_RATE)
+        .with_tone(args.tone)
+        .with_volume(args.volume);
+    if args.headless {
+        let max_cycles = args.max_cycles.expect("--headless requires --max-cycles");
+        return run_headless(
+            cpu,
+            max_cycles,
+            args.cycles_per_frame,
+            args.dump_screen,
+            args.wav,
+            audio,
+        );
+    }
+    eprintln!("ROM hash {:016x}", rom_hash);
+    let keymap = load_keymap(args.keymap.as_deref(), rom_hash);
+    let mut gamepad = open_gamepad();
+
+    let audio = match args.mute {
+        true => None,
+        false => Some(create_audio(audio)),
+    };
+    let mut window = create_window(
+        cpu.display_width() * args.scale,
+        cpu.display_height() * args.scale,
+    );
+    let mut renderer = Renderer::new(args.palette)
+        .with_overlay(args.overlay)
+        .with_persistence(args.persistence);
+    let mut blender = FrameBlender::new(args.blend);
+    let mut save_slot = 0;
+    let mut rewind = Rewind::new(REWIND_FRAMES);
+    let mut fault = None;
+    let (mut debugger, debugger_commands) = match args.debug {
+        true => {
+            let mut debugger = Debugger::new();
+            debugger.pause();
+            print_registers(&cpu);
+            print_prompt();
+            (Some(debugger), Some(spawn_debugger_prompt()))
+        }
+        false => (None, None),
+    };
+
+    while window.is_open() && !window.is_key_down(Key::Escape) && !cpu.halted() {
+        let previous_fault = fault;
+        if update_save_states(&window, &mut cpu, &rom_location, &mut save_slot) {
+            fault = None;
+        }
+        if let (Some(debugger), Some(commands)) = (&mut debugger, &debugger_commands) {
+            update_debugger(commands, debugger, &cpu);
+        }
+        update_keys(&window, &mut cpu, &keymap, &mut gamepad);
+        update_cpu(
+            &window,
+            &mut cpu,
+            &mut rewind,
+            &mut fault,
+            debugger.as_mut(),
+            args.cycles_per_frame,
+            args.timing,
+        );
+        if fault != previous_fault {
+            update_title(&mut window, fault);
+        }
+        if let Some((_, _, audio)) = &audio {
+            audio.lock().unwrap().update(&cpu);
+        }
+        update_window(&mut cpu, &mut window, &mut blender, &mut renderer);
+    }
+}
+
+/// Runs without a window or audio for a fixed number of cycles, then writes the
+/// screen as text to `dump_screen`, or stdout if no path was given. The sound
+/// is recorded to `wav` if a path is given.
+fn run_headless(
+    mut cpu: chip8::Cpu,
+    cycles: u64,
+    cycles_per_frame: u32,
+    dump_screen: Option<String>,
+    wav: Option<String>,
+    mut audio: AudioSource,
+) {
+    let result = match &wav {
+        Some(_) => headless::render_audio(&mut cpu, cycles, cycles_per_frame, &mut audio),
+        None => headless::run(&mut cpu, cycles, cycles_per_frame).map(|_| Vec::new()),
+    };
+    let samples = match result {
+        Ok(samples) => samples,
+        Err(err) => {
+            eprintln!("CPU fault: {}", err);
+            std::process::exit(1);
+        }
+    };
+    if let Some(path) = wav {
+        let written = File::create(&path)
+            .and_then(|file| audio::write_wav(BufWriter::new(file), audio.sample_rate(), &samples));
+        if let Err(err) = written {
+            eprintln!("Failed to write {}: {}", path, err);
+            std::process::exit(1);
+        }
+    }
+    let screen = headless::screen_text(&cpu);
+    match dump_screen {
+        Some(path) => {
+            if let Err(err) = std::fs::write(&path, screen) {
+                eprintln!("Failed to write {}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
+        None => print!("{}", screen),
+    }
+}
+
+/// Prints a listing of a rom.
+fn disassemble(matches: &ArgMatches) {
+    let rom_location = matches.value_of("rom").unwrap();
+    let syntax = match matches.value_of("syntax") {
+        Some("octo") => instruction::Syntax::Octo,
+        _ => instruction::Syntax::Cowgod,
+    };
+    match std::fs::read(rom_location) {
+        Ok(rom) => print!("{}", disassembler::disassemble(&rom, syntax)),
+        Err(err) => {
+            eprintln!("Failed to read {}: {}", rom_location, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Builds a source file into a rom, written next to the source with a `.ch8`
+/// extension unless `-o` is given.
+fn assemble(matches: &ArgMatches, compile: impl Fn(&str) -> Result<Vec<u8>, String>) {
+    let source_location = matches.value_of("source").unwrap();
+    let rom_location = matches
+        .value_of("output")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(source_location).with_extension("ch8"));
+    let result = std::fs::read_to_string(source_location)
+        .map_err(|err| err.to_string())
+        .and_then(|source| compile(&source))
+        .and_then(|rom| std::fs::write(&rom_location, rom).map_err(|err| err.to_string()));
+    if let Err(err) = result {
+        eprintln!("Failed to assemble {}: {}", source_location, err);
+        std::process::exit(1);
+    }
+}
+
+/// Prints a rom's size and hash, how much of it is code, and the platform its
+/// instructions need.
+fn info(matches: &ArgMatches) {
+    let rom_location = matches.value_of("rom").unwrap();
+    let rom = match read_rom(rom_location) {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("Failed to load {}: {}", rom_location, err);
+            std::process::exit(1);
+        }
+    };
+    let code = disassembler::trace(&rom);
+    let code_size: usize = code.values().map(Instruction::size).sum();
+    let unknown = code
+        .values()
+        .filter(|instruction| matches!(instruction, Instruction::Unknown(_)))
+        .count();
+    let (platform, preset) = platform(code.values());
+    println!("Size:     {} bytes", rom.len());
+    println!("Hash:     {:016x}", keymap::rom_hash(&rom));
+    println!(
+        "Code:     {} instructions in {} bytes",
+        code.len(),
+        code_size
+    );
+    println!("Platform: {} (--quirks {})", platform, preset);
+    if unknown > 0 {
+        println!("Unknown:  {} opcodes", unknown);
+    }
+}
+
+/// The first platform whose instruction set covers `code`, and the quirks
+/// preset for it.
+fn platform<'a>(code: impl Iterator<Item = &'a Instruction>) -> (&'static str, &'static str) {
+    const PLATFORMS: [(&str, &str); 3] = [
+        ("CHIP-8", "vip"),
+        ("SUPER-CHIP", "schip"),
+        ("XO-CHIP", "xochip"),
+    ];
+    let level = code
+        .map(|instruction| match instruction {
+            Instruction::SaveRange(..)
+            | Instruction::LoadRange(..)
+            | Instruction::LongLoadI
+            | Instruction::SelectPlanes(_)
+            | Instruction::LoadAudio
+            | Instruction::SetPitch(_) => 2,
+            Instruction::ScrollDown(_)
+            | Instruction::ScrollRight
+            | Instruction::ScrollLeft
+            | Instruction::Exit
+            | Instruction::LowRes
+            | Instruction::HighRes
+            | Instruction::Draw(_, _, 0)
+            | Instruction::LoadBigFont(_)
+            | Instruction::StoreFlags(_)
+            | Instruction::LoadFlags(_) => 1,
+            _ => 0,
+        })
+        .max()
+        .unwrap_or(0);
+    PLATFORMS[level]
+}
+
+/// Runs a rom headless and compares its screen with a snapshot written by
+/// `run --headless --dump-screen`, exiting with an error if they differ.
+fn test_rom(matches: &ArgMatches) {
+    let rom_location = matches.value_of("rom").unwrap();
+    let expect = matches.value_of("expect").unwrap();
+    let quirks = parsed(matches, "quirks").unwrap_or_default();
+    let seed = parsed(matches, "seed").unwrap_or(TEST_SEED);
+    let max_cycles = parsed(matches, "max-cycles").unwrap();
+    let (mut cpu, _) = load_cpu(rom_location, quirks, Some(seed));
+    if let Err(err) = headless::run(&mut cpu, max_cycles, cycles_per_frame(matches)) {
+        eprintln!("CPU fault: {}", err);
+        std::process::exit(1);
+    }
+    let screen = headless::screen_text(&cpu);
+
+    if matches.is_present("update") {
+        if let Err(err) = std::fs::write(expect, &screen) {
+            eprintln!("Failed to write {}: {}", expect, err);
+            std::process::exit(1);
+        }
+        return println!("Updated {}", expect);
+    }
+    match std::fs::read_to_string(expect) {
+        Ok(expected) if expected == screen => println!("{} matches {}", rom_location, expect),
+        Ok(_) => {
+            eprint!("{} does not match {}:\n{}", rom_location, expect, screen);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("Failed to read {}: {}", expect, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Reads a rom, compiling it first if it is Octo source ending in `.8o`.
+fn read_rom(rom_location: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if Path::new(rom_location).extension() == Some(OCTO_EXTENSION.as_ref()) {
+        return Ok(octo::compile(&std::fs::read_to_string(rom_location)?)?);
+    }
+    Ok(std::fs::read(rom_location)?)
+}
+
+/// `$XDG_CONFIG_HOME/chip8-rs/keymap.toml`, falling back to `~/.config`.
+fn default_keymap_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join(CONFIG_DIR).join(KEYMAP_FILE))
+}
+
+/// Loads the keymap for a rom from the given config file, or from the default
+/// config file if it exists. Exits if the config can't be read.
+fn load_keymap(path: Option<&str>, rom_hash: u64) -> Keymap {
+    let path = match path.map(PathBuf::from) {
+        Some(path) => path,
+        None => match default_keymap_path() {
+            Some(path) if path.exists() => path,
+            _ => return Keymap::default(),
+        },
+    };
+    let config = std::fs::read_to_string(&path)
+        .map_err(|err| err.to_string())
+        .and_then(|text| KeymapConfig::parse(&text).map_err(|err| err.to_string()));
+    match config {
+        Ok(config) => config.keymap(rom_hash),
+        Err(err) => {
+            eprintln!("Failed to load keymap {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Runs a frame and records it, or steps back a frame while Backspace is held.
+/// Execution stays halted after a fault until the user rewinds or loads a state.
+fn update_cpu(
+    window: &Window,
+    cpu: &mut chip8::Cpu,
+    rewind: &mut Rewind,
+    fault: &mut Option<chip8::CpuError>,
+    debugger: Option<&mut Debugger>,
+    cycles_per_frame: u32,
+    timing: Timing,
+) {
+    if window.is_key_down(Key::Backspace) {
+        if let Some(state) = rewind.step_back() {
+            cpu.load_state(state)
+                .expect("rewind only holds states saved by the cpu");
+            *fault = None;
+        }
+    } else if fault.is_none() {
+        let result = match debugger {
+            Some(debugger) => run_debugger_frame(cpu, debugger, cycles_per_frame),
+            None => match timing {
+                Timing::Flat => cpu.run_frame(cycles_per_frame),
+                Timing::Vip => cpu.run_vip_frame(),
+            }
+            .map(|()| true),
+        };
+        match result {
+            Ok(true) => rewind.push(cpu.save_state()),
+            Ok(false) => {}
+            Err(err) => {
+                eprintln!("CPU fault: {}", err);
+                *fault = Some(err);
+            }
+        }
+    }
+}
+
+/// Runs a frame under the debugger. Returns whether the whole frame ran.
+fn run_debugger_frame(
+    cpu: &mut chip8::Cpu,
+    debugger: &mut Debugger,
+    cycles_per_frame: u32,
+) -> Result<bool, chip8::CpuError> {
+    if debugger.paused() {
+        return Ok(false);
+    }
+    match debugger.run(cpu, cycles_per_frame) {
+        Some(StopReason::Fault(err)) => return Err(err),
+        Some(reason) => {
+            if reason != StopReason::Step {
+                println!("Stopped: {}", reason);
+            }
+            print_registers(cpu);
+            print_prompt();
+        }
+        None => {}
+    }
+    match debugger.paused() {
+        true => Ok(false),
+        false => {
+            cpu.tick_timers();
+            Ok(true)
+        }
+    }
+}
+
+/// Reads debugger commands from stdin on a separate thread so the window stays live.
+fn spawn_debugger_prompt() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
 }
 
-fn update_audio(cpu: &chip8::Cpu, sink: &Sink) {
-    match cpu.beep() {
-        true => sink.play(),
-        false => sink.pause(),
+fn print_prompt() {
+    print!("(chip8) ");
+    std::io::stdout().flush().unwrap();
+}
+
+fn update_debugger(commands: &Receiver<String>, debugger: &mut Debugger, cpu: &chip8::Cpu) {
+    while let Ok(line) = commands.try_recv() {
+        if !line.trim().is_empty() {
+            match line.parse() {
+                Ok(command) => execute_debugger_command(command, debugger, cpu),
+                Err(err) => eprintln!("{}", err),
+            }
+        }
+        if debugger.paused() {
+            print_prompt();
+        }
+    }
+}
+
+fn execute_debugger_command(command: Command, debugger: &mut Debugger, cpu: &chip8::Cpu) {
+    match command {
+        Command::Continue => debugger.resume(),
+        Command::Pause => {
+            debugger.pause();
+            print_registers(cpu);
+        }
+        Command::Step => debugger.step(),
+        Command::StepOver => debugger.step_over(cpu),
+        Command::StepOut => debugger.step_out(cpu),
+        Command::Break(address) => debugger.add_breakpoint(address),
+        Command::Delete(address) => {
+            if !debugger.remove_breakpoint(address) {
+                eprintln!("No breakpoint at 0x{:04X}", address);
+            }
+        }
+        Command::Watch(address, watch) => debugger.add_watchpoint(address, watch),
+        Command::Unwatch(address) => {
+            if !debugger.remove_watchpoint(address) {
+                eprintln!("No watchpoint at 0x{:04X}", address);
+            }
+        }
+        Command::Condition(condition) => debugger.add_condition(condition, cpu),
+        Command::DeleteCondition(index) => {
+            if debugger.remove_condition(index).is_none() {
+                eprintln!("No condition {}", index);
+            }
+        }
+        Command::Registers => print_registers(cpu),
+        Command::Memory(address, len) => print_memory(cpu, address, len),
+        Command::List => {
+            for address in debugger.breakpoints() {
+                println!("break 0x{:04X}", address);
+            }
+            for (address, watch) in debugger.watchpoints() {
+                println!("watch 0x{:04X} {:?}", address, watch);
+            }
+            for (index, condition) in debugger.conditions().enumerate() {
+                println!("cond {}: {}", index, condition);
+            }
+        }
+        Command::Help => println!("{}", Command::HELP),
+    }
+}
+
+fn print_registers(cpu: &chip8::Cpu) {
+    let v = cpu
+        .v()
+        .iter()
+        .enumerate()
+        .map(|(index, value)| format!("v{:X}={:02X}", index, value))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let pc = cpu.program_counter() as usize;
+    let opcode = match cpu.memory().get(pc..(pc + 2)) {
+        Some(bytes) => {
+            let opcode = ((bytes[0] as u16) << 8) | bytes[1] as u16;
+            format!("{:04X} {}", opcode, instruction::decode(opcode))
+        }
+        None => "----".to_string(),
+    };
+    println!(
+        "pc={:04X} [{}] i={:04X} dt={:02X} st={:02X} stack={:04X?}",
+        pc,
+        opcode,
+        cpu.i(),
+        cpu.delay_timer(),
+        cpu.sound_timer(),
+        cpu.stack()
+    );
+    println!("{}", v);
+}
+
+fn print_memory(cpu: &chip8::Cpu, address: usize, len: usize) {
+    let memory = cpu.memory();
+    let end = address.saturating_add(len).min(memory.len());
+    for row_start in (address.min(end)..end).step_by(16) {
+        let row = &memory[row_start..(row_start + 16).min(end)];
+        let bytes = row
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        println!("{:04X}: {}", row_start, bytes);
+    }
+}
+
+fn update_title(window: &mut Window, fault: Option<chip8::CpuError>) {
+    match fault {
+        Some(err) => window.set_title(&format!("{} - halted: {}", WINDOW_TITLE, err)),
+        None => window.set_title(WINDOW_TITLE),
+    }
+}
+
+/// F5 saves and F9 loads the current slot, F6 and F7 select the previous and next slot.
+/// Returns whether a state was loaded.
+fn update_save_states(
+    window: &Window,
+    cpu: &mut chip8::Cpu,
+    rom_location: &str,
+    slot: &mut u8,
+) -> bool {
+    if window.is_key_pressed(Key::F6, KeyRepeat::No) {
+        *slot = (*slot + SAVE_SLOTS - 1) % SAVE_SLOTS;
+        println!("Selected save slot {}", slot);
+    }
+    if window.is_key_pressed(Key::F7, KeyRepeat::No) {
+        *slot = (*slot + 1) % SAVE_SLOTS;
+        println!("Selected save slot {}", slot);
+    }
+
+    let path = save_state_path(rom_location, *slot);
+    if window.is_key_pressed(Key::F5, KeyRepeat::No) {
+        match std::fs::write(&path, cpu.save_state()) {
+            Ok(()) => println!("Saved state to {}", path.display()),
+            Err(err) => eprintln!("Failed to save state to {}: {}", path.display(), err),
+        }
+    }
+    if window.is_key_pressed(Key::F9, KeyRepeat::No) {
+        match std::fs::read(&path) {
+            Ok(state) => match cpu.load_state(&state) {
+                Ok(()) => {
+                    println!("Loaded state from {}", path.display());
+                    return true;
+                }
+                Err(err) => eprintln!("Failed to load state from {}: {}", path.display(), err),
+            },
+            Err(err) => eprintln!("Failed to read state from {}: {}", path.display(), err),
+        }
     }
+    false
 }
 
-fn create_window() -> Window {
+fn save_state_path(rom_location: &str, slot: u8) -> PathBuf {
+    Path::new(rom_location).with_extension(format!("state{}", slot))
+}
+
+#[cfg(target_os = "linux")]
+fn open_gamepad() -> Gamepad<Box<dyn InputBackend>> {
+    let evdev = chip8_rs::gamepad::Evdev::open();
+    for name in evdev.names() {
+        eprintln!("Using gamepad {}", name);
+    }
+    Gamepad::new(Box::new(evdev))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_gamepad() -> Gamepad<Box<dyn InputBackend>> {
+    Gamepad::new(Box::new(chip8_rs::gamepad::Mock::default()))
+}
+
+fn update_keys(
+    window: &Window,
+    cpu: &mut chip8::Cpu,
+    keymap: &Keymap,
+    gamepad: &mut Gamepad<Box<dyn InputBackend>>,
+) {
+    if let Err(err) = gamepad.poll() {
+        eprintln!("Failed to read gamepad: {}", err);
+    }
+    let mut key_values = gamepad.keys(keymap);
+    if let Some(keys) = window.get_keys() {
+        // Keymaps name keys the way minifb's Debug output does
+        key_values.extend(
+            keys.into_iter()
+                .filter_map(|key| keymap.key(&format!("{:?}", key)))
+                .map(usize::from),
+        );
+    }
+    cpu.set_keys(key_values);
+}
+
+/// Feeds the core's audio source to rodio, which pulls samples on its own thread.
+struct SharedSource(Arc<Mutex<AudioSource>>);
+
+impl Iterator for SharedSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.0.lock().unwrap().next()
+    }
+}
+
+impl Source for SharedSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.0.lock().unwrap().sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+fn create_audio(source: AudioSource) -> (OutputStream, Sink, Arc<Mutex<AudioSource>>) {
+    let (stream, handle) = OutputStream::try_default().unwrap();
+    let sink = Sink::try_new(&handle).unwrap();
+    let source = Arc::new(Mutex::new(source));
+    sink.append(SharedSource(source.clone()));
+    (stream, sink, source)
+}
+
+fn create_window(width: usize, height: usize) -> Window {
     let mut window = Window::new(
-        "Chip-8",
-        WINDOW_WIDTH,
-        WINDOW_HEIGHT,
-        WindowOptions::default(),
+        WINDOW_TITLE,
+        width,
+        height,
+        WindowOptions {
+            resize: true,
+            // The renderer scales the display itself
+            scale_mode: ScaleMode::UpperLeft,
+            ..WindowOptions::default()
+        },
     )
     .unwrap();
-    window.limit_update_rate(Some(Duration::from_secs_f64(1. / FRAMES_PER_SEC)));
+    window.limit_update_rate(Some(Duration::from_secs_f64(1. / FRAMES_PER_SEC as f64)));
     window
 }
 
-fn update_window(cpu: &chip8::Cpu, window: &mut Window) {
-    let buffer = cpu
-        .display()
-        .iter()
-        .map(|x| match x {
-            true => 255,
-            false => 0,
-        })
-        .collect::<Vec<_>>();
-    window
-        .update_with_buffer(&buffer, chip8::DISPLAY_WIDTH, chip8::DISPLAY_HEIGHT)
-        .unwrap();
+fn update_window(
+    cpu: &mut chip8::Cpu,
+    window: &mut Window,
+    blender: &mut FrameBlender,
+    renderer: &mut Renderer,
+) {
+    let (width, height) = window.get_size();
+    let changed = blender.update(cpu);
+    // Unchanged frames aren't uploaded again, and minimized windows have nowhere to
+    // draw, but either way input still needs polling
+    if width == 0 || height == 0 || (!changed && renderer.is_current(width, height)) {
+        return window.update();
+    }
+    let buffer = renderer.render(blender.display(), blender.display_width(), width, height);
+    window.update_with_buffer(buffer, width, height).unwrap();
 }
@@ -0,0 +1,837 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+const PROGRAM_START: usize = 0x200;
+const MAX_ADDRESS: i64 = 0xFFF;
+const MAX_LONG_ADDRESS: i64 = 0xFFFF;
+const MAX_NIBBLE: i64 = 0xF;
+const MIN_BYTE: i64 = -0x80;
+const MAX_BYTE: i64 = 0xFF;
+const V_FLAG: u16 = 0xF;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    UnexpectedEnd,
+    UnexpectedToken(String),
+    UnknownSymbol(String),
+    DuplicateSymbol(String),
+    InvalidRegister(String),
+    InvalidExpression(String),
+    OutOfRange { value: i64, min: i64, max: i64 },
+    UnmatchedBlock(String),
+    UnclosedBlock,
+    MissingMain,
+    Unsupported(String),
+    RecursiveMacro(String),
+}
+
+/// An error in Octo source, with the 1-based line it was found on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub line: usize,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            ErrorKind::UnexpectedEnd => write!(f, "unexpected end of source"),
+            ErrorKind::UnexpectedToken(token) => write!(f, "unexpected {}", token),
+            ErrorKind::UnknownSymbol(symbol) => write!(f, "unknown symbol {}", symbol),
+            ErrorKind::DuplicateSymbol(symbol) => write!(f, "{} is already defined", symbol),
+            ErrorKind::InvalidRegister(register) => write!(f, "{} is not a register", register),
+            ErrorKind::InvalidExpression(token) => write!(f, "invalid expression at {}", token),
+            ErrorKind::OutOfRange { value, min, max } => {
+                write!(f, "value {} is out of range {}..={}", value, min, max)
+            }
+            ErrorKind::UnmatchedBlock(token) => write!(f, "{} without a matching block", token),
+            ErrorKind::UnclosedBlock => write!(f, "block is never closed"),
+            ErrorKind::MissingMain => write!(f, "program has no main label"),
+            ErrorKind::Unsupported(token) => write!(f, "{} is not supported", token),
+            ErrorKind::RecursiveMacro(name) => write!(f, "macro {} expands itself", name),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Compiles Octo source into a ROM loaded at 0x200.
+///
+/// Supports labels, `:=` style register statements, `if ... then`,
+/// `if ... begin ... else ... end`, `loop ... while ... again`, `:const`,
+/// `:alias`, `:macro`, `:calc`, `:byte`, `:pointer`, `:org`, `:next`,
+/// `:unpack` and the SUPER-CHIP and XO-CHIP statements the core implements.
+/// As in Octo, `<`, `>`, `<=` and `>=` comparisons clobber `vf`, and
+/// `:calc` evaluates operators right to left without precedence.
+///
+/// The program starts with a jump to the `main` label.
+pub fn compile(source: &str) -> Result<Vec<u8>, CompileError> {
+    let mut compiler = Compiler::new(tokenize(source));
+    compiler.emit(0x1000);
+    while let Some(token) = compiler.tokens.pop_front() {
+        compiler.line = token.line;
+        compiler.statement(token)?;
+    }
+    if !compiler.blocks.is_empty() {
+        return Err(compiler.error(ErrorKind::UnclosedBlock));
+    }
+    let main = match compiler.symbols.get("main") {
+        Some(main) => *main as i64,
+        None => return Err(compiler.error(ErrorKind::MissingMain)),
+    };
+    compiler.patch(
+        PROGRAM_START,
+        0x1000 | compiler.check(main, 0, MAX_ADDRESS)?,
+    );
+    compiler.resolve_fixups()?;
+    Ok(compiler.rom)
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    /// The macros being expanded when this token was produced, outermost first
+    expanding: Vec<String>,
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (index, line) in source.lines().enumerate() {
+        let code = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        tokens.extend(code.split_whitespace().map(|text| Token {
+            text: text.to_string(),
+            line: index + 1,
+            expanding: Vec::new(),
+        }));
+    }
+    tokens
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// A reference to a label that had not been defined when it was used.
+struct Fixup {
+    address: usize,
+    name: String,
+    line: usize,
+    kind: FixupKind,
+}
+
+enum FixupKind {
+    /// The low 12 bits of the opcode at `address`
+    Address,
+    /// The whole word at `address`
+    Long,
+    /// The byte operand of `vX := NN` that takes a nibble and the top of an address
+    UnpackHigh(u8),
+    /// The byte operand of `vX := NN` that takes the bottom of an address
+    UnpackLow,
+}
+
+enum Block {
+    If(usize),
+    Else(usize),
+    Loop { start: usize, breaks: Vec<usize> },
+}
+
+struct Condition {
+    x: u16,
+    op: Token,
+    rhs: Option<Token>,
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>,
+    line: usize,
+    rom: Vec<u8>,
+    here: usize,
+    symbols: HashMap<String, f64>,
+    aliases: HashMap<String, u16>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    blocks: Vec<Block>,
+}
+
+impl Compiler {
+    fn new(tokens: VecDeque<Token>) -> Self {
+        Self {
+            tokens,
+            line: 1,
+            rom: Vec::new(),
+            here: PROGRAM_START,
+            symbols: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+        }
+    }
+
+    fn error(&self, kind: ErrorKind) -> CompileError {
+        CompileError {
+            line: self.line,
+            kind,
+        }
+    }
+
+    fn next(&mut self) -> Result<Token, CompileError> {
+        let token = self
+            .tokens
+            .pop_front()
+            .ok_or_else(|| self.error(ErrorKind::UnexpectedEnd))?;
+        self.line = token.line;
+        Ok(token)
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), CompileError> {
+        let token = self.next()?;
+        match token.text == text {
+            true => Ok(()),
+            false => Err(self.error(ErrorKind::UnexpectedToken(token.text))),
+        }
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let offset = self.here - PROGRAM_START;
+        if self.rom.len() <= offset {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+        self.here += 1;
+    }
+
+    fn emit(&mut self, opcode: u16) {
+        for byte in opcode.to_be_bytes().iter() {
+            self.emit_byte(*byte);
+        }
+    }
+
+    /// ORs `value` into the word at `address`, whose operand bits are zero.
+    fn patch(&mut self, address: usize, value: u16) {
+        let offset = address - PROGRAM_START;
+        self.rom[offset] |= (value >> 8) as u8;
+        self.rom[offset + 1] |= value as u8;
+    }
+
+    fn define(&mut self, name: Token, value: f64) -> Result<(), CompileError> {
+        match self.symbols.insert(name.text.clone(), value) {
+            Some(_) => Err(self.error(ErrorKind::DuplicateSymbol(name.text))),
+            None => Ok(()),
+        }
+    }
+
+    fn check(&self, value: i64, min: i64, max: i64) -> Result<u16, CompileError> {
+        match (min..=max).contains(&value) {
+            true => Ok(value as u16),
+            false => Err(self.error(ErrorKind::OutOfRange { value, min, max })),
+        }
+    }
+
+    /// Checks that a block's jump can reach `address`.
+    fn block_target(&self, address: usize) -> Result<u16, CompileError> {
+        self.check(address as i64, 0, MAX_ADDRESS)
+    }
+
+    fn lookup(&self, text: &str) -> Option<f64> {
+        parse_number(text).or_else(|| self.symbols.get(text).copied())
+    }
+
+    fn value(&self, token: &Token, min: i64, max: i64) -> Result<u16, CompileError> {
+        match self.lookup(&token.text) {
+            Some(value) => self.check(value as i64, min, max),
+            None => Err(self.error(ErrorKind::UnknownSymbol(token.text.clone()))),
+        }
+    }
+
+    fn byte(&self, token: &Token) -> Result<u16, CompileError> {
+        Ok(self.value(token, MIN_BYTE, MAX_BYTE)? & 0xFF)
+    }
+
+    fn is_register(&self, text: &str) -> bool {
+        register_index(text).is_some() || self.aliases.contains_key(text)
+    }
+
+    fn register(&self, token: &Token) -> Result<u16, CompileError> {
+        register_index(&token.text)
+            .or_else(|| self.aliases.get(&token.text).copied())
+            .ok_or_else(|| self.error(ErrorKind::InvalidRegister(token.text.clone())))
+    }
+
+    fn next_register(&mut self) -> Result<u16, CompileError> {
+        let token = self.next()?;
+        self.register(&token)
+    }
+
+    fn next_value(&mut self, min: i64, max: i64) -> Result<u16, CompileError> {
+        let token = self.next()?;
+        self.value(&token, min, max)
+    }
+
+    /// Emits `opcode` with a label or address operand, deferring the operand
+    /// until the end of compilation if the label isn't defined yet.
+    fn emit_reference(&mut self, opcode: u16, kind: FixupKind) -> Result<(), CompileError> {
+        let token = self.next()?;
+        let address = self.here;
+        let operand = match self.lookup(&token.text) {
+            Some(value) => self.resolve(value as i64, &kind)?,
+            None => {
+                self.fixups.push(Fixup {
+                    address,
+                    name: token.text,
+                    line: token.line,
+                    kind,
+                });
+                0
+            }
+        };
+        self.emit(opcode | operand);
+        Ok(())
+    }
+
+    fn resolve(&self, target: i64, kind: &FixupKind) -> Result<u16, CompileError> {
+        Ok(match kind {
+            FixupKind::Address => self.check(target, 0, MAX_ADDRESS)?,
+            FixupKind::Long => self.check(target, 0, MAX_LONG_ADDRESS)?,
+            FixupKind::UnpackHigh(nibble) => {
+                ((*nibble as u16) << 4) | (self.check(target, 0, MAX_ADDRESS)? >> 8)
+            }
+            FixupKind::UnpackLow => self.check(target, 0, MAX_ADDRESS)? & 0xFF,
+        })
+    }
+
+    fn resolve_fixups(&mut self) -> Result<(), CompileError> {
+        for fixup in std::mem::take(&mut self.fixups) {
+            self.line = fixup.line;
+            let target = match self.symbols.get(&fixup.name) {
+                Some(target) => *target as i64,
+                None => return Err(self.error(ErrorKind::UnknownSymbol(fixup.name))),
+            };
+            let operand = self.resolve(target, &fixup.kind)?;
+            self.patch(fixup.address, operand);
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), CompileError> {
+        match token.text.as_str() {
+            ":" => {
+                let name = self.next()?;
+                self.define(name, self.here as f64)?;
+            }
+            ":next" => {
+                let name = self.next()?;
+                self.define(name, (self.here + 1) as f64)?;
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.next()?;
+                match self.lookup(&value.text) {
+                    Some(value) => self.define(name, value)?,
+                    None => return Err(self.error(ErrorKind::UnknownSymbol(value.text))),
+                }
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let register = self.next_register()?;
+                self.aliases.insert(name.text, register);
+            }
+            ":macro" => self.define_macro()?,
+            ":calc" => {
+                let name = self.next()?;
+                let value = self.calc()?;
+                self.define(name, value)?;
+            }
+            ":byte" => {
+                let value = match self.peek() {
+                    Some("{") => self.calc()?,
+                    _ => {
+                        let token = self.next()?;
+                        self.lookup(&token.text).ok_or_else(|| {
+                            self.error(ErrorKind::UnknownSymbol(token.text.clone()))
+                        })?
+                    }
+                };
+                let byte = self.check(value as i64, MIN_BYTE, MAX_BYTE)? & 0xFF;
+                self.emit_byte(byte as u8);
+            }
+            ":pointer" => self.emit_reference(0, FixupKind::Long)?,
+            ":org" => {
+                let address = self.next_value(PROGRAM_START as i64, MAX_LONG_ADDRESS)?;
+                self.here = address as usize;
+            }
+            ":call" => self.emit_reference(0x2000, FixupKind::Address)?,
+            ":unpack" => {
+                let nibble = self.next_value(0, MAX_NIBBLE)? as u8;
+                let label = self.next()?;
+                self.tokens.push_front(label.clone());
+                self.emit_reference(0x6000, FixupKind::UnpackHigh(nibble))?;
+                self.tokens.push_front(label);
+                self.emit_reference(0x6100, FixupKind::UnpackLow)?;
+            }
+            ":breakpoint" | ":proto" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            "clear" => self.emit(0x00E0),
+            "return" | ";" => self.emit(0x00EE),
+            "scroll-down" => {
+                let n = self.next_value(0, MAX_NIBBLE)?;
+                self.emit(0x00C0 | n);
+            }
+            "scroll-right" => self.emit(0x00FB),
+            "scroll-left" => self.emit(0x00FC),
+            "exit" => self.emit(0x00FD),
+            "lores" => self.emit(0x00FE),
+            "hires" => self.emit(0x00FF),
+            "native" => self.emit_reference(0x0000, FixupKind::Address)?,
+            "jump" => self.emit_reference(0x1000, FixupKind::Address)?,
+            "jump0" => self.emit_reference(0xB000, FixupKind::Address)?,
+            "sprite" => {
+                let x = self.next_register()?;
+                let y = self.next_register()?;
+                let n = self.next_value(0, MAX_NIBBLE)?;
+                self.emit(0xD000 | (x << 8) | (y << 4) | n);
+            }
+            "save" | "load" => {
+                let x = self.next_register()?;
+                let (range, single) = match token.text.as_str() {
+                    "save" => (0x5002, 0xF055),
+                    _ => (0x5003, 0xF065),
+                };
+                match self.peek() {
+                    Some("-") => {
+                        self.next()?;
+                        let y = self.next_register()?;
+                        self.emit(range | (x << 8) | (y << 4));
+                    }
+                    _ => self.emit(single | (x << 8)),
+                }
+            }
+            "saveflags" => {
+                let x = self.next_register()?;
+                self.emit(0xF075 | (x << 8));
+            }
+            "loadflags" => {
+                let x = self.next_register()?;
+                self.emit(0xF085 | (x << 8));
+            }
+            "bcd" => {
+                let x = self.next_register()?;
+                self.emit(0xF033 | (x << 8));
+            }
+            "plane" => {
+                let n = self.next_value(0, MAX_NIBBLE)?;
+                self.emit(0xF001 | (n << 8));
+            }
+            "audio" => self.emit(0xF002),
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.next_register()?;
+                let opcode = match token.text.as_str() {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                };
+                self.emit(opcode | (x << 8));
+            }
+            "i" => self.index_statement()?,
+            "if" => self.if_statement()?,
+            "else" => match self.blocks.pop() {
+                Some(Block::If(jump)) => {
+                    let end = self.here;
+                    self.emit(0x1000);
+                    self.patch(jump, self.block_target(self.here)?);
+                    self.blocks.push(Block::Else(end));
+                }
+                _ => return Err(self.error(ErrorKind::UnmatchedBlock(token.text))),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If(jump)) | Some(Block::Else(jump)) => {
+                    self.patch(jump, self.block_target(self.here)?)
+                }
+                _ => return Err(self.error(ErrorKind::UnmatchedBlock(token.text))),
+            },
+            "loop" => self.blocks.push(Block::Loop {
+                start: self.here,
+                breaks: Vec::new(),
+            }),
+            "while" => {
+                let condition = self.condition()?;
+                self.emit_condition(&condition, true)?;
+                let jump = self.here;
+                self.emit(0x1000);
+                match self.blocks.iter_mut().rev().find_map(|block| match block {
+                    Block::Loop { breaks, .. } => Some(breaks),
+                    _ => None,
+                }) {
+                    Some(breaks) => breaks.push(jump),
+                    None => return Err(self.error(ErrorKind::UnmatchedBlock(token.text))),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, breaks }) => {
+                    self.emit(0x1000 | self.block_target(start)?);
+                    let end = self.block_target(self.here)?;
+                    for jump in breaks {
+                        self.patch(jump, end);
+                    }
+                }
+                _ => return Err(self.error(ErrorKind::UnmatchedBlock(token.text))),
+            },
+            "scroll-up" => return Err(self.error(ErrorKind::Unsupported(token.text))),
+            text if text.starts_with(':') => {
+                return Err(self.error(ErrorKind::Unsupported(token.text)))
+            }
+            text if self.is_register(text) => self.register_statement(&token)?,
+            text if self.macros.contains_key(text) => self.expand_macro(&token)?,
+            text if parse_number(text).is_some() => {
+                let byte = self.byte(&token)?;
+                self.emit_byte(byte as u8);
+            }
+            _ => {
+                // Any other word is a call to the subroutine with that label
+                self.tokens.push_front(token);
+                self.emit_reference(0x2000, FixupKind::Address)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn index_statement(&mut self) -> Result<(), CompileError> {
+        let op = self.next()?;
+        match op.text.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    let x = self.next_register()?;
+                    self.emit(0xF029 | (x << 8));
+                }
+                Some("bighex") => {
+                    self.next()?;
+                    let x = self.next_register()?;
+                    self.emit(0xF030 | (x << 8));
+                }
+                Some("long") => {
+                    self.next()?;
+                    self.emit(0xF000);
+                    self.emit_reference(0, FixupKind::Long)?;
+                }
+                _ => self.emit_reference(0xA000, FixupKind::Address)?,
+            },
+            "+=" => {
+                let x = self.next_register()?;
+                self.emit(0xF01E | (x << 8));
+            }
+            _ => return Err(self.error(ErrorKind::UnexpectedToken(op.text))),
+        }
+        Ok(())
+    }
+
+    fn register_statement(&mut self, register: &Token) -> Result<(), CompileError> {
+        let x = self.register(register)? << 8;
+        let op = self.next()?;
+        let rhs = self.next()?;
+        let y = match self.is_register(&rhs.text) {
+            true => Some(self.register(&rhs)? << 4),
+            false => None,
+        };
+        let opcode = match (op.text.as_str(), rhs.text.as_str(), y) {
+            (":=", _, Some(y)) => 0x8000 | x | y,
+            (":=", "random", None) => {
+                let mask = self.next()?;
+                0xC000 | x | self.byte(&mask)?
+            }
+            (":=", "key", None) => 0xF00A | x,
+            (":=", "delay", None) => 0xF007 | x,
+            (":=", _, None) => 0x6000 | x | self.byte(&rhs)?,
+            ("+=", _, Some(y)) => 0x8004 | x | y,
+            ("+=", _, None) => 0x7000 | x | self.byte(&rhs)?,
+            ("-=", _, Some(y)) => 0x8005 | x | y,
+            ("-=", _, None) => 0x7000 | x | (self.byte(&rhs)?.wrapping_neg() & 0xFF),
+            ("=-", _, Some(y)) => 0x8007 | x | y,
+            ("|=", _, Some(y)) => 0x8001 | x | y,
+            ("&=", _, Some(y)) => 0x8002 | x | y,
+            ("^=", _, Some(y)) => 0x8003 | x | y,
+            (">>=", _, Some(y)) => 0x8006 | x | y,
+            ("<<=", _, Some(y)) => 0x800E | x | y,
+            ("=-", _, None)
+            | ("|=", _, None)
+            | ("&=", _, None)
+            | ("^=", _, None)
+            | (">>=", _, None)
+            | ("<<=", _, None) => return Err(self.error(ErrorKind::InvalidRegister(rhs.text))),
+            _ => return Err(self.error(ErrorKind::UnexpectedToken(op.text))),
+        };
+        self.emit(opcode);
+        Ok(())
+    }
+
+    fn condition(&mut self) -> Result<Condition, CompileError> {
+        let x = self.next_register()?;
+        let op = self.next()?;
+        let rhs = match op.text.as_str() {
+            "key" | "-key" => None,
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => Some(self.next()?),
+            _ => return Err(self.error(ErrorKind::UnexpectedToken(op.text))),
+        };
+        Ok(Condition { x, op, rhs })
+    }
+
+    /// Emits a skip so the following instruction only runs when the condition
+    /// holds, or only when it doesn't if `negate` is set.
+    fn emit_condition(&mut self, condition: &Condition, negate: bool) -> Result<(), CompileError> {
+        let x = condition.x << 8;
+        let op = condition.op.text.as_str();
+        let rhs = match &condition.rhs {
+            Some(rhs) => rhs,
+            None => {
+                let skip_if_pressed = (op == "-key") != negate;
+                self.emit(x | if skip_if_pressed { 0xE09E } else { 0xE0A1 });
+                return Ok(());
+            }
+        };
+        let y = match self.is_register(&rhs.text) {
+            true => Some(self.register(rhs)?),
+            false => None,
+        };
+        match op {
+            "==" | "!=" => {
+                let skip_if_equal = (op == "==") == negate;
+                let opcode = match (skip_if_equal, y) {
+                    (true, Some(y)) => 0x5000 | x | (y << 4),
+                    (false, Some(y)) => 0x9000 | x | (y << 4),
+                    (true, None) => 0x3000 | x | self.byte(rhs)?,
+                    (false, None) => 0x4000 | x | self.byte(rhs)?,
+                };
+                self.emit(opcode);
+            }
+            _ => {
+                // Compare by subtracting into vf and testing the borrow flag
+                match y {
+                    Some(y) => self.emit(0x8000 | (V_FLAG << 8) | (y << 4)),
+                    None => {
+                        let byte = self.byte(rhs)?;
+                        self.emit(0x6000 | (V_FLAG << 8) | byte);
+                    }
+                }
+                let subtract = match op {
+                    "<" | ">=" => 0x8007,
+                    _ => 0x8005,
+                };
+                self.emit(subtract | (V_FLAG << 8) | (condition.x << 4));
+                let run_when_borrow = matches!(op, "<" | ">") != negate;
+                self.emit((V_FLAG << 8) | if run_when_borrow { 0x4000 } else { 0x3000 });
+            }
+        }
+        Ok(())
+    }
+
+    fn if_statement(&mut self) -> Result<(), CompileError> {
+        let condition = self.condition()?;
+        let terminator = self.next()?;
+        match terminator.text.as_str() {
+            "then" => self.emit_condition(&condition, false)?,
+            "begin" => {
+                self.emit_condition(&condition, true)?;
+                self.blocks.push(Block::If(self.here));
+                self.emit(0x1000);
+            }
+            _ => return Err(self.error(ErrorKind::UnexpectedToken(terminator.text))),
+        }
+        Ok(())
+    }
+
+    /// Takes the tokens of a `{ ... }` group, without the outer braces.
+    fn group(&mut self) -> Result<Vec<Token>, CompileError> {
+        self.expect("{")?;
+        let mut depth = 1;
+        let mut tokens = Vec::new();
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                return Ok(tokens);
+            }
+            tokens.push(token);
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), CompileError> {
+        let name = self.next()?;
+        let mut params = Vec::new();
+        while !matches!(self.peek(), Some("{") | None) {
+            params.push(self.next()?.text);
+        }
+        let body = self.group()?;
+        self.macros.insert(name.text, Macro { params, body });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &Token) -> Result<(), CompileError> {
+        if name.expanding.contains(&name.text) {
+            return Err(self.error(ErrorKind::RecursiveMacro(name.text.clone())));
+        }
+        let mut expanding = name.expanding.clone();
+        expanding.push(name.text.clone());
+        let param_count = self.macros[&name.text].params.len();
+        let args = (0..param_count)
+            .map(|_| self.next().map(|token| token.text))
+            .collect::<Result<Vec<_>, _>>()?;
+        let definition = &self.macros[&name.text];
+        let expansion = definition
+            .body
+            .iter()
+            .map(|token| {
+                let text = match definition.params.iter().position(|p| p == &token.text) {
+                    Some(index) => args[index].clone(),
+                    None => token.text.clone(),
+                };
+                Token {
+                    text,
+                    line: name.line,
+                    expanding: expanding.clone(),
+                }
+            })
+            .collect::<Vec<_>>();
+        for token in expansion.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    fn calc(&mut self) -> Result<f64, CompileError> {
+        let tokens = self.group()?;
+        let mut position = 0;
+        let value = self.calc_expression(&tokens, &mut position)?;
+        match tokens.get(position) {
+            Some(token) => Err(self.error(ErrorKind::InvalidExpression(token.text.clone()))),
+            None => Ok(value),
+        }
+    }
+
+    /// Binary operators all share one precedence and group from the right.
+    fn calc_expression(&self, tokens: &[Token], position: &mut usize) -> Result<f64, CompileError> {
+        let left = self.calc_term(tokens, position)?;
+        let op = match tokens.get(*position) {
+            Some(token) if token.text != ")" => token.text.as_str(),
+            _ => return Ok(left),
+        };
+        *position += 1;
+        let right = self.calc_expression(tokens, position)?;
+        let (a, b) = (left as i64, right as i64);
+        Ok(match op {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => (a << b) as f64,
+            ">>" => (a >> b) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => (left < right) as u8 as f64,
+            ">" => (left > right) as u8 as f64,
+            "<=" => (left <= right) as u8 as f64,
+            ">=" => (left >= right) as u8 as f64,
+            "==" => (left == right) as u8 as f64,
+            "!=" => (left != right) as u8 as f64,
+            _ => return Err(self.error(ErrorKind::InvalidExpression(op.to_string()))),
+        })
+    }
+
+    fn calc_term(&self, tokens: &[Token], position: &mut usize) -> Result<f64, CompileError> {
+        let token = match tokens.get(*position) {
+            Some(token) => token,
+            None => return Err(self.error(ErrorKind::UnexpectedEnd)),
+        };
+        *position += 1;
+        let unary: Option<fn(f64) -> f64> = match token.text.as_str() {
+            "-" => Some(|value| -value),
+            "~" => Some(|value| !(value as i64) as f64),
+            "!" => Some(|value| (value == 0.) as u8 as f64),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "floor" => Some(f64::floor),
+            "ceil" => Some(f64::ceil),
+            _ => None,
+        };
+        if let Some(unary) = unary {
+            return Ok(unary(self.calc_term(tokens, position)?));
+        }
+        match token.text.as_str() {
+            "(" => {
+                let value = self.calc_expression(tokens, position)?;
+                match tokens.get(*position) {
+                    Some(close) if close.text == ")" => {
+                        *position += 1;
+                        Ok(value)
+                    }
+                    _ => Err(self.error(ErrorKind::InvalidExpression(token.text.clone()))),
+                }
+            }
+            "@" => {
+                let address = self.calc_term(tokens, position)? as usize;
+                let byte = address
+                    .checked_sub(PROGRAM_START)
+                    .and_then(|offset| self.rom.get(offset));
+                Ok(byte.copied().unwrap_or(0) as f64)
+            }
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            "HERE" => Ok(self.here as f64),
+            text => self
+                .lookup(text)
+                .ok_or_else(|| self.error(ErrorKind::UnknownSymbol(token.text.clone()))),
+        }
+    }
+}
+
+fn register_index(text: &str) -> Option<u16> {
+    let digit = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+    match digit.len() {
+        1 => u16::from_str_radix(digit, 16).ok(),
+        _ => None,
+    }
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value } as f64)
+}
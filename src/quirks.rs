@@ -0,0 +1,122 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Behaviour of the opcodes that different interpreters disagree on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8XY6/8XYE shift VY into VX instead of shifting VX in place.
+    pub shift_uses_vy: bool,
+    /// FX55/FX65 leave I pointing one past the last register accessed.
+    pub load_store_increments_i: bool,
+    /// With `load_store_increments_i`, FX55/FX65 leave I pointing at the last
+    /// register accessed instead, adding X rather than X + 1.
+    pub load_store_increments_i_by_x: bool,
+    /// BNNN is treated as BXNN and jumps to XNN + VX instead of NNN + V0.
+    pub jump_uses_vx: bool,
+    /// DXYN clips sprites at the screen edge instead of wrapping them.
+    pub clip_sprites: bool,
+    /// 8XY1/8XY2/8XY3 reset VF to 0.
+    pub vf_reset: bool,
+    /// FX1E sets VF when I is moved past the end of addressable memory.
+    pub i_overflow_sets_vf: bool,
+    /// Memory is 64K instead of 4K, as XO-CHIP programs expect.
+    pub extended_memory: bool,
+    /// FX0A waits for a key to be pressed and released, instead of returning
+    /// any key that is held.
+    pub wait_key_release: bool,
+    /// DXYN waits for the next 60Hz vertical blank before drawing, limiting
+    /// programs to one sprite per frame.
+    pub display_wait: bool,
+}
+
+impl Quirks {
+    pub const COSMAC_VIP: Self = Self {
+        shift_uses_vy: true,
+        load_store_increments_i: true,
+        load_store_increments_i_by_x: false,
+        jump_uses_vx: false,
+        clip_sprites: true,
+        vf_reset: true,
+        i_overflow_sets_vf: false,
+        extended_memory: false,
+        wait_key_release: true,
+        display_wait: true,
+    };
+
+    pub const CHIP_48: Self = Self {
+        shift_uses_vy: false,
+        load_store_increments_i: true,
+        load_store_increments_i_by_x: true,
+        jump_uses_vx: true,
+        clip_sprites: true,
+        vf_reset: false,
+        i_overflow_sets_vf: false,
+        extended_memory: false,
+        wait_key_release: true,
+        display_wait: false,
+    };
+
+    pub const SUPER_CHIP: Self = Self {
+        shift_uses_vy: false,
+        load_store_increments_i: false,
+        load_store_increments_i_by_x: false,
+        jump_uses_vx: true,
+        clip_sprites: true,
+        vf_reset: false,
+        i_overflow_sets_vf: true,
+        extended_memory: false,
+        wait_key_release: true,
+        display_wait: false,
+    };
+
+    pub const XO_CHIP: Self = Self {
+        shift_uses_vy: true,
+        load_store_increments_i: true,
+        load_store_increments_i_by_x: false,
+        jump_uses_vx: false,
+        clip_sprites: false,
+        vf_reset: false,
+        i_overflow_sets_vf: false,
+        extended_memory: true,
+        wait_key_release: true,
+        display_wait: false,
+    };
+
+    pub const PRESET_NAMES: [&'static str; 4] = ["vip", "chip48", "schip", "xochip"];
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self::COSMAC_VIP
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownPreset(pub String);
+
+impl fmt::Display for UnknownPreset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unknown quirks preset '{}', expected one of: {}",
+            self.0,
+            Quirks::PRESET_NAMES.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnknownPreset {}
+
+impl FromStr for Quirks {
+    type Err = UnknownPreset;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "vip" | "cosmac-vip" | "chip8" => Ok(Self::COSMAC_VIP),
+            "chip48" | "chip-48" => Ok(Self::CHIP_48),
+            "schip" | "superchip" | "super-chip" => Ok(Self::SUPER_CHIP),
+            "xochip" | "xo-chip" => Ok(Self::XO_CHIP),
+            _ => Err(UnknownPreset(s.to_string())),
+        }
+    }
+}
@@ -0,0 +1,597 @@
+use crate::chip8::Cpu;
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+/// Overlays are only drawn once pixels are this many times their size, so
+/// small windows aren't swamped by them.
+const MIN_GRID_SCALE: usize = 3;
+const MIN_SCANLINE_SCALE: usize = 2;
+/// Brightness kept by pixels under a grid line or scanline.
+const OVERLAY_SHADE: f32 = 0.6;
+const CHANNELS: usize = 3;
+/// Most frames an erased display is held back for by [`Blend::Draws`], so a
+/// program that leaves the screen cleared is still shown.
+const MAX_HELD_FRAMES: u32 = 3;
+
+/// Colors in 0RGB for each combination of lit bit planes: unlit, the first
+/// plane, the second plane and both planes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub colors: [u32; 4],
+}
+
+impl Palette {
+    pub const CLASSIC: Self = Self {
+        colors: [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555],
+    };
+
+    /// The themes offered by Octo.
+    pub const OCTO: Self = Self {
+        colors: [0x996600, 0xFFCC00, 0xFF6600, 0x662200],
+    };
+    pub const LCD: Self = Self {
+        colors: [0xF9FFB3, 0x3D8026, 0xABCC47, 0x00131A],
+    };
+    pub const HOTDOG: Self = Self {
+        colors: [0x000000, 0xFF0000, 0xFFFF00, 0xFFFFFF],
+    };
+    pub const GRAY: Self = Self {
+        colors: [0xAAAAAA, 0x000000, 0xFFFFFF, 0x666666],
+    };
+    pub const CGA: Self = Self {
+        colors: [0x000000, 0xFF00FF, 0x00FFFF, 0xFFFFFF],
+    };
+
+    pub const PRESET_NAMES: [&'static str; 6] = ["classic", "octo", "lcd", "hotdog", "gray", "cga"];
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::CLASSIC
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidPalette(pub String);
+
+impl fmt::Display for InvalidPalette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid palette '{}', expected one of: {}, or 2 or 4 comma separated RRGGBB colors",
+            self.0,
+            Palette::PRESET_NAMES.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for InvalidPalette {}
+
+impl FromStr for Palette {
+    type Err = InvalidPalette;
+
+    /// Parses a preset name, or a background and foreground color optionally
+    /// followed by the second plane and blended colors, as in `000000,FFFFFF`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "classic" => return Ok(Self::CLASSIC),
+            "octo" => return Ok(Self::OCTO),
+            "lcd" => return Ok(Self::LCD),
+            "hotdog" => return Ok(Self::HOTDOG),
+            "gray" | "grey" => return Ok(Self::GRAY),
+            "cga" => return Ok(Self::CGA),
+            _ => {}
+        }
+        let colors = s
+            .split(',')
+            .map(|color| {
+                let color = color.trim().trim_start_matches('#');
+                match color.len() {
+                    6 => u32::from_str_radix(color, 16).ok(),
+                    _ => None,
+                }
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| InvalidPalette(s.to_string()))?;
+        match colors[..] {
+            [background, foreground] => Ok(Self {
+                colors: [
+                    background,
+                    foreground,
+                    Self::CLASSIC.colors[2],
+                    Self::CLASSIC.colors[3],
+                ],
+            }),
+            [background, foreground, second, both] => Ok(Self {
+                colors: [background, foreground, second, both],
+            }),
+            _ => Err(InvalidPalette(s.to_string())),
+        }
+    }
+}
+
+/// Lines drawn over the scaled display to mimic a CRT or LCD.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overlay {
+    #[default]
+    None,
+    /// Darkens the bottom and right edge of every pixel.
+    Grid,
+    /// Darkens the bottom edge of every row of pixels.
+    Scanlines,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownOverlay(pub String);
+
+impl fmt::Display for UnknownOverlay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unknown overlay '{}', expected none, grid or scanlines",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownOverlay {}
+
+impl FromStr for Overlay {
+    type Err = UnknownOverlay;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Overlay::None),
+            "grid" => Ok(Overlay::Grid),
+            "scanlines" => Ok(Overlay::Scanlines),
+            _ => Err(UnknownOverlay(s.to_string())),
+        }
+    }
+}
+
+/// How the frames a program draws are combined before being shown, to hide
+/// the flicker of sprites being erased and redrawn.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Blend {
+    #[default]
+    Off,
+    /// Shows pixels lit in any of the last N frames.
+    Frames(usize),
+    /// Keeps showing the previous frame while the display is erased part way
+    /// through a redraw, until the next sprite is drawn.
+    Draws,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownBlend(pub String);
+
+impl fmt::Display for UnknownBlend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unknown blend '{}', expected off, draws or a number of frames",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownBlend {}
+
+impl FromStr for Blend {
+    type Err = UnknownBlend;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(Blend::Off),
+            "draws" => Ok(Blend::Draws),
+            frames => match frames.parse() {
+                Ok(0) | Ok(1) => Ok(Blend::Off),
+                Ok(frames) => Ok(Blend::Frames(frames)),
+                Err(_) => Err(UnknownBlend(s.to_string())),
+            },
+        }
+    }
+}
+
+/// Picks the display to present each frame according to a [`Blend`].
+#[derive(Debug, Clone, Default)]
+pub struct FrameBlender {
+    blend: Blend,
+    history: VecDeque<Vec<u8>>,
+    held_frames: u32,
+    display: Vec<u8>,
+    display_width: usize,
+}
+
+impl FrameBlender {
+    pub fn new(blend: Blend) -> Self {
+        Self {
+            blend,
+            ..Self::default()
+        }
+    }
+
+    /// Takes the CPU's display at the end of a frame and returns whether the
+    /// display to present has changed.
+    pub fn update(&mut self, cpu: &mut Cpu) -> bool {
+        let modified = cpu.take_display_modified();
+        let display = cpu.display();
+        let resized =
+            cpu.display_width() != self.display_width || display.len() != self.display.len();
+        if resized {
+            self.history.clear();
+            self.held_frames = 0;
+            self.display_width = cpu.display_width();
+        }
+
+        match self.blend {
+            Blend::Off => {
+                if !modified && !resized {
+                    return false;
+                }
+                self.display = display.to_vec();
+            }
+            Blend::Frames(frames) => {
+                if self.history.len() == frames {
+                    self.history.pop_front();
+                }
+                self.history.push_back(display.to_vec());
+                let mut blended = vec![0; display.len()];
+                for frame in &self.history {
+                    for (pixel, planes) in blended.iter_mut().zip(frame) {
+                        *pixel |= planes;
+                    }
+                }
+                if blended == self.display {
+                    return false;
+                }
+                self.display = blended;
+            }
+            Blend::Draws => {
+                let pending = modified || self.held_frames > 0;
+                if pending && cpu.display_erased() {
+                    self.held_frames += 1;
+                }
+                let held = cpu.display_erased() && self.held_frames <= MAX_HELD_FRAMES;
+                if !resized && (!pending || held) {
+                    return false;
+                }
+                self.held_frames = 0;
+                self.display = display.to_vec();
+            }
+        }
+        true
+    }
+
+    /// The display to present, as plane masks like [`Cpu::display`].
+    pub fn display(&self) -> &[u8] {
+        &self.display
+    }
+
+    pub fn display_width(&self) -> usize {
+        self.display_width
+    }
+}
+
+/// Draws the CPU's display into a 0RGB framebuffer of any size.
+///
+/// The display is scaled by the largest whole number that fits, keeping
+/// pixels square, and centred with the background color around it.
+#[derive(Debug, Clone, Default)]
+pub struct Renderer {
+    palette: Palette,
+    overlay: Overlay,
+    persistence: f32,
+
+    /// Displayed color of each display pixel, which lags behind the palette
+    /// color while it fades.
+    glow: Vec<[f32; CHANNELS]>,
+    glow_width: usize,
+    fading: bool,
+    frame: Vec<u32>,
+    frame_size: (usize, usize),
+}
+
+impl Renderer {
+    pub fn new(palette: Palette) -> Self {
+        Self {
+            palette,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_overlay(mut self, overlay: Overlay) -> Self {
+        self.overlay = overlay;
+        self
+    }
+
+    /// Sets how much of a pixel's old color is kept each frame after it's
+    /// erased, like the phosphor of a CRT. At 0 pixels change instantly,
+    /// while values around 0.5 hide the flicker of sprites being erased and
+    /// redrawn.
+    pub fn with_persistence(mut self, persistence: f32) -> Self {
+        self.persistence = persistence.clamp(0., 1.);
+        self
+    }
+
+    pub fn palette(&self) -> Palette {
+        self.palette
+    }
+
+    /// Whether the last frame rendered still shows the same display at this
+    /// size, with no pixels left fading, so it needn't be rendered again.
+    pub fn is_current(&self, width: usize, height: usize) -> bool {
+        !self.fading && self.frame_size == (width, height)
+    }
+
+    /// Renders a frame of the display, given as plane masks like
+    /// [`Cpu::display`](crate::chip8::Cpu::display), into a `width` by
+    /// `height` buffer. Call once per frame so pixels fade at a steady rate.
+    pub fn render(
+        &mut self,
+        display: &[u8],
+        display_width: usize,
+        width: usize,
+        height: usize,
+    ) -> &[u32] {
+        let display_height = display.len() / display_width;
+        self.update_glow(display, display_width);
+
+        let scale = (width / display_width).min(height / display_height).max(1);
+        let left = width.saturating_sub(display_width * scale) / 2;
+        let top = height.saturating_sub(display_height * scale) / 2;
+
+        self.frame.clear();
+        self.frame.resize(width * height, self.palette.colors[0]);
+        self.frame_size = (width, height);
+        for (index, glow) in self.glow.iter().enumerate() {
+            let color = to_color(*glow);
+            let x = left + (index % display_width) * scale;
+            let y = top + (index / display_width) * scale;
+            for dy in 0..scale.min(height.saturating_sub(y)) {
+                let row = (y + dy) * width;
+                for dx in 0..scale.min(width.saturating_sub(x)) {
+                    let edge_x = dx == scale - 1;
+                    let edge_y = dy == scale - 1;
+                    let shaded = match self.overlay {
+                        Overlay::None => false,
+                        Overlay::Grid => scale >= MIN_GRID_SCALE && (edge_x || edge_y),
+                        Overlay::Scanlines => scale >= MIN_SCANLINE_SCALE && edge_y,
+                    };
+                    self.frame[row + x + dx] = match shaded {
+                        true => shade(color, OVERLAY_SHADE),
+                        false => color,
+                    };
+                }
+            }
+        }
+        &self.frame
+    }
+
+    fn update_glow(&mut self, display: &[u8], display_width: usize) {
+        // Fading between resolutions would smear the old display over the new one
+        if self.glow.len() != display.len() || self.glow_width != display_width {
+            self.glow = vec![[0.; CHANNELS]; display.len()];
+            self.glow_width = display_width;
+            self.persist(display, 0.);
+        } else {
+            self.persist(display, self.persistence);
+        }
+    }
+
+    fn persist(&mut self, display: &[u8], persistence: f32) {
+        self.fading = false;
+        for (glow, planes) in self.glow.iter_mut().zip(display) {
+            let target = to_channels(self.palette.colors[*planes as usize & 0b11]);
+            // Only erased pixels fade, moving all channels together from the
+            // color they were lit in toward the background
+            let persistence = match *planes & 0b11 {
+                0 => persistence,
+                _ => 0.,
+            };
+            for (channel, target) in glow.iter_mut().zip(target.iter()) {
+                *channel = target + (*channel - target) * persistence;
+            }
+            // Stop once the difference no longer shows in 8 bit color
+            if glow
+                .iter()
+                .zip(target.iter())
+                .all(|(channel, target)| (channel - target).abs() < 0.5)
+            {
+                *glow = target;
+            }
+            self.fading |= *glow != target;
+        }
+    }
+}
+
+fn to_channels(color: u32) -> [f32; CHANNELS] {
+    [
+        ((color >> 16) & 0xFF) as f32,
+        ((color >> 8) & 0xFF) as f32,
+        (color & 0xFF) as f32,
+    ]
+}
+
+fn to_color(channels: [f32; CHANNELS]) -> u32 {
+    channels
+        .iter()
+        .fold(0, |color, channel| (color << 8) | channel.round() as u32)
+}
+
+fn shade(color: u32, brightness: f32) -> u32 {
+    let mut channels = to_channels(color);
+    for channel in channels.iter_mut() {
+        *channel *= brightness;
+    }
+    to_color(channels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Quirks;
+
+    const BLACK: u32 = 0x000000;
+    const WHITE: u32 = 0xFFFFFF;
+
+    #[test]
+    fn palettes_parse_presets_and_hex_colors() {
+        assert_eq!("Octo".parse(), Ok(Palette::OCTO));
+        assert_eq!(
+            "#102030,405060".parse::<Palette>().unwrap().colors[..2],
+            [0x102030, 0x405060]
+        );
+        assert_eq!(
+            "000000,111111,222222,333333"
+                .parse::<Palette>()
+                .unwrap()
+                .colors,
+            [0x000000, 0x111111, 0x222222, 0x333333]
+        );
+        assert!("000000".parse::<Palette>().is_err());
+        assert!("blue".parse::<Palette>().is_err());
+    }
+
+    #[test]
+    fn scales_by_whole_numbers_and_centres() {
+        let mut renderer = Renderer::new(Palette::CLASSIC);
+        // A 2x1 display with its right pixel lit, in a 7x5 window, scales 3x
+        let frame = renderer.render(&[0, 1], 2, 7, 5).to_vec();
+        let rows: Vec<_> = frame.chunks(7).collect();
+        assert_eq!(rows[0], [BLACK; 7]);
+        for row in &rows[1..4] {
+            assert_eq!(row, &[BLACK, BLACK, BLACK, WHITE, WHITE, WHITE, BLACK]);
+        }
+        assert_eq!(rows[4], [BLACK; 7]);
+    }
+
+    #[test]
+    fn overlays_shade_pixel_edges() {
+        let mut renderer = Renderer::new(Palette::CLASSIC).with_overlay(Overlay::Grid);
+        let grey = shade(WHITE, OVERLAY_SHADE);
+        let frame = renderer.render(&[1], 1, 3, 3).to_vec();
+        assert_eq!(
+            frame,
+            [WHITE, WHITE, grey, WHITE, WHITE, grey, grey, grey, grey]
+        );
+
+        let mut renderer = Renderer::new(Palette::CLASSIC).with_overlay(Overlay::Scanlines);
+        let frame = renderer.render(&[1], 1, 2, 2).to_vec();
+        assert_eq!(frame, [WHITE, WHITE, grey, grey]);
+    }
+
+    #[test]
+    fn persistence_fades_pixels_out_but_not_in() {
+        let mut renderer = Renderer::new(Palette::CLASSIC).with_persistence(0.5);
+        assert_eq!(renderer.render(&[1], 1, 1, 1), [WHITE]);
+        assert_eq!(renderer.render(&[0], 1, 1, 1), [0x808080]);
+        assert_eq!(renderer.render(&[0], 1, 1, 1), [0x404040]);
+        assert_eq!(renderer.render(&[1], 1, 1, 1), [WHITE]);
+
+        // Switching resolution starts afresh
+        assert_eq!(renderer.render(&[0, 0], 2, 2, 1), [BLACK, BLACK]);
+    }
+
+    #[test]
+    fn persistence_fades_toward_light_backgrounds() {
+        let mut renderer = Renderer::new(Palette::LCD).with_persistence(0.5);
+        assert_eq!(renderer.render(&[1], 1, 1, 1), [0x3D8026]);
+        assert_eq!(renderer.render(&[0], 1, 1, 1), [0x9BC06D]);
+        assert_eq!(renderer.render(&[0], 1, 1, 1), [0xCADF90]);
+        assert_eq!(renderer.render(&[1], 1, 1, 1), [0x3D8026]);
+
+        let mut renderer = Renderer::new(Palette::GRAY).with_persistence(0.5);
+        assert_eq!(renderer.render(&[2], 1, 1, 1), [WHITE]);
+        assert_eq!(renderer.render(&[0], 1, 1, 1), [0xD5D5D5]);
+        assert_eq!(renderer.render(&[1], 1, 1, 1), [BLACK]);
+    }
+
+    /// Draws the font's 0 at the top left, erases it, redraws it, then erases
+    /// it for good.
+    fn flickering_cpu() -> Cpu {
+        let rom = [
+            0xF0, 0x29, 0xD0, 0x15, 0xD0, 0x15, 0xD0, 0x15, 0xD0, 0x15, 0x12, 0x0A,
+        ];
+        let quirks = Quirks {
+            display_wait: false,
+            ..Quirks::default()
+        };
+        Cpu::from_bytes(&rom, quirks).unwrap()
+    }
+
+    fn run(cpu: &mut Cpu, cycles: usize) {
+        for _ in 0..cycles {
+            cpu.cycle().unwrap();
+        }
+    }
+
+    #[test]
+    fn blending_draws_holds_erased_frames() {
+        let mut cpu = flickering_cpu();
+        let mut blender = FrameBlender::new(Blend::Draws);
+        assert!(blender.update(&mut cpu));
+        assert!(blender.display().iter().all(|pixel| *pixel == 0));
+
+        run(&mut cpu, 2);
+        assert!(blender.update(&mut cpu));
+        assert_eq!(blender.display()[0], 1);
+        run(&mut cpu, 1);
+        assert!(!blender.update(&mut cpu));
+        assert_eq!(blender.display()[0], 1);
+        run(&mut cpu, 1);
+        assert!(blender.update(&mut cpu));
+
+        // A display left erased is shown once it has been held long enough
+        run(&mut cpu, 1);
+        for _ in 0..MAX_HELD_FRAMES {
+            assert!(!blender.update(&mut cpu));
+        }
+        assert!(blender.update(&mut cpu));
+        assert_eq!(blender.display()[0], 0);
+        assert!(!blender.update(&mut cpu));
+    }
+
+    #[test]
+    fn blending_frames_ors_them() {
+        let mut cpu = flickering_cpu();
+        let mut blender = FrameBlender::new(Blend::Frames(2));
+        assert!(blender.update(&mut cpu));
+        run(&mut cpu, 2);
+        assert!(blender.update(&mut cpu));
+        run(&mut cpu, 1);
+        assert!(!blender.update(&mut cpu));
+        assert_eq!(blender.display()[0], 1);
+        assert!(blender.update(&mut cpu));
+        assert_eq!(blender.display()[0], 0);
+    }
+
+    #[test]
+    fn unchanged_frames_are_not_blended_again() {
+        let mut cpu = flickering_cpu();
+        let mut blender = FrameBlender::new(Blend::Off);
+        assert!(blender.update(&mut cpu));
+        assert!(!blender.update(&mut cpu));
+        run(&mut cpu, 1);
+        assert!(!blender.update(&mut cpu));
+        run(&mut cpu, 1);
+        assert!(blender.update(&mut cpu));
+
+        let mut renderer = Renderer::new(Palette::CLASSIC).with_persistence(0.5);
+        assert!(!renderer.is_current(64, 32));
+        renderer.render(blender.display(), blender.display_width(), 64, 32);
+        assert!(renderer.is_current(64, 32));
+        assert!(!renderer.is_current(128, 64));
+        renderer.render(&[0; 64 * 32], 64, 64, 32);
+        assert!(!renderer.is_current(64, 32));
+    }
+
+    #[test]
+    fn blends_parse() {
+        assert_eq!("draws".parse(), Ok(Blend::Draws));
+        assert_eq!("3".parse(), Ok(Blend::Frames(3)));
+        assert_eq!("1".parse(), Ok(Blend::Off));
+        assert!("often".parse::<Blend>().is_err());
+    }
+}
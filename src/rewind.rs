@@ -0,0 +1,213 @@
+use std::collections::VecDeque;
+
+/// Ring buffer of per-frame save states for stepping backwards through gameplay.
+///
+/// Only the most recent state is kept in full. Every older state is stored as
+/// the run length encoded XOR of itself and the state that followed it, which
+/// is mostly zeros since little changes from one frame to the next.
+pub struct Rewind {
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Delta>,
+}
+
+struct Delta {
+    len: usize,
+    runs: Vec<u8>,
+}
+
+impl Rewind {
+    /// Creates a buffer that can step back at most `capacity` frames.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            latest: None,
+            deltas: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.latest.take() {
+            if self.deltas.len() == self.capacity {
+                self.deltas.pop_front();
+            }
+            if self.capacity > 0 {
+                self.deltas.push_back(Delta {
+                    len: previous.len(),
+                    runs: encode(&xor(&previous, &state)),
+                });
+            }
+        }
+        self.latest = Some(state);
+    }
+
+    /// Drops the most recent state and returns the one before it, or `None` if
+    /// there is nothing older to go back to.
+    pub fn step_back(&mut self) -> Option<&[u8]> {
+        let delta = self.deltas.pop_back()?;
+        let latest = self.latest.as_mut()?;
+        let mut previous = xor(latest, &decode(&delta.runs));
+        previous.truncate(delta.len);
+        *latest = previous;
+        Some(latest)
+    }
+
+    /// Number of frames that can currently be stepped back.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+    }
+}
+
+/// XORs two buffers, treating the shorter one as if it were padded with zeros.
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let len = a.len().max(b.len());
+    (0..len)
+        .map(|index| a.get(index).unwrap_or(&0) ^ b.get(index).unwrap_or(&0))
+        .collect()
+}
+
+/// Encodes a buffer as alternating (zero count, literal count, literals) runs.
+fn encode(bytes: &[u8]) -> Vec<u8> {
+    let mut runs = Vec::new();
+    let mut index = 0;
+    while index < bytes.len() {
+        let zeros = bytes[index..].iter().take_while(|byte| **byte == 0).count();
+        index += zeros;
+        let literals = bytes[index..].iter().take_while(|byte| **byte != 0).count();
+        write_varint(&mut runs, zeros);
+        write_varint(&mut runs, literals);
+        runs.extend_from_slice(&bytes[index..(index + literals)]);
+        index += literals;
+    }
+    runs
+}
+
+fn decode(mut runs: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    while !runs.is_empty() {
+        let zeros = read_varint(&mut runs);
+        let literals = read_varint(&mut runs);
+        bytes.resize(bytes.len() + zeros, 0);
+        bytes.extend_from_slice(&runs[..literals]);
+        runs = &runs[literals..];
+    }
+    bytes
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[0];
+        *bytes = &bytes[1..];
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A state that changes a few bytes from frame to frame.
+    fn state(frame: usize) -> Vec<u8> {
+        let mut state = vec![0x55; 300];
+        state[frame % 300] = frame as u8;
+        state[299] = (frame * 7) as u8;
+        state
+    }
+
+    #[test]
+    fn steps_back_through_every_state() {
+        let mut rewind = Rewind::new(10);
+        for frame in 0..=10 {
+            rewind.push(state(frame));
+        }
+        assert_eq!(rewind.len(), 10);
+        for frame in (0..10).rev() {
+            assert_eq!(rewind.step_back(), Some(&state(frame)[..]));
+        }
+        assert!(rewind.is_empty());
+        assert_eq!(rewind.step_back(), None);
+    }
+
+    #[test]
+    fn evicts_the_oldest_states_at_capacity() {
+        let mut rewind = Rewind::new(3);
+        for frame in 0..10 {
+            rewind.push(state(frame));
+        }
+        assert_eq!(rewind.len(), 3);
+        for frame in (6..9).rev() {
+            assert_eq!(rewind.step_back(), Some(&state(frame)[..]));
+        }
+        assert_eq!(rewind.step_back(), None);
+    }
+
+    #[test]
+    fn states_can_change_length() {
+        let states = [
+            vec![1, 2, 3],
+            vec![1, 2, 3, 0, 0, 9],
+            vec![],
+            vec![0, 0],
+            vec![7],
+        ];
+        let mut rewind = Rewind::new(states.len());
+        for state in &states {
+            rewind.push(state.clone());
+        }
+        for state in states.iter().rev().skip(1) {
+            assert_eq!(rewind.step_back(), Some(&state[..]));
+        }
+    }
+
+    #[test]
+    fn repeated_states_have_empty_deltas() {
+        let mut rewind = Rewind::new(2);
+        rewind.push(state(1));
+        rewind.push(state(1));
+        assert_eq!(rewind.deltas[0].runs, encode(&[0; 300]));
+        assert_eq!(rewind.step_back(), Some(&state(1)[..]));
+    }
+
+    #[test]
+    fn run_length_encoding_round_trips() {
+        let long_run = [vec![0; 1000], vec![1; 200], vec![0; 3]].concat();
+        let cases: [&[u8]; 5] = [&[], &[0, 0, 0], &[4, 5, 6], &[0, 1, 0, 0, 2, 2], &long_run];
+        for bytes in cases {
+            assert_eq!(decode(&encode(bytes)), bytes);
+        }
+        assert!(encode(&[]).is_empty());
+        // 1000 zeros, 200 literals, 3 zeros and no literals
+        assert_eq!(encode(&long_run).len(), 2 + 2 + 200 + 2);
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing_to_step_back_to() {
+        let mut rewind = Rewind::new(0);
+        rewind.push(state(0));
+        rewind.push(state(1));
+        assert_eq!(rewind.step_back(), None);
+    }
+}
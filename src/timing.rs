@@ -0,0 +1,172 @@
+use crate::chip8::FRAMES_PER_SEC;
+use crate::instruction::Instruction;
+use std::fmt;
+use std::str::FromStr;
+
+/// The VIP's CDP1802 runs at 1.76064 MHz, taking 8 clocks per machine cycle.
+pub const VIP_CLOCK_HZ: u32 = 1_760_640;
+const CLOCKS_PER_MACHINE_CYCLE: u32 = 8;
+/// Machine cycles in each frame.
+pub const VIP_CYCLES_PER_FRAME: u32 = VIP_CLOCK_HZ / CLOCKS_PER_MACHINE_CYCLE / FRAMES_PER_SEC;
+/// Machine cycles taken from the interpreter every frame by the CDP1861's
+/// display DMA, 8 bytes for each of 128 scanlines, and its interrupt routine.
+const DISPLAY_CYCLES: u32 = 8 * 128 + 46;
+/// Machine cycles left for the interpreter in each frame.
+pub const VIP_INTERPRETER_CYCLES_PER_FRAME: u32 = VIP_CYCLES_PER_FRAME - DISPLAY_CYCLES;
+
+/// Fetching an instruction and jumping to its routine.
+const FETCH_CYCLES: u32 = 40;
+/// Moving past the next instruction when a skip is taken.
+const SKIP_CYCLES: u32 = 4;
+/// 00E0 zeroes all 256 bytes of the display.
+const CLEAR_CYCLES: u32 = 24 + 256 * 3;
+/// FX55 and FX65 copy one register per iteration.
+const REGISTER_COPY_CYCLES: u32 = 14;
+const DRAW_SETUP_CYCLES: u32 = 68;
+const DRAW_ROW_CYCLES: u32 = 20;
+/// Rows not aligned to a display byte are shifted one bit at a time, then
+/// written to two bytes instead of one.
+const DRAW_SHIFT_CYCLES: u32 = 4;
+const DRAW_UNALIGNED_ROW_CYCLES: u32 = 10;
+
+/// How long instructions take to run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Timing {
+    /// Every instruction takes the same time, at a fixed rate.
+    #[default]
+    Flat,
+    /// Instructions take an estimate of the machine cycles they took on the
+    /// COSMAC VIP, run in real time at its clock rate with
+    /// [`Cpu::run_vip_frame`].
+    ///
+    /// [`Cpu::run_vip_frame`]: crate::chip8::Cpu::run_vip_frame
+    Vip,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownTiming(pub String);
+
+impl fmt::Display for UnknownTiming {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown timing '{}', expected flat or vip", self.0)
+    }
+}
+
+impl std::error::Error for UnknownTiming {}
+
+impl FromStr for Timing {
+    type Err = UnknownTiming;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "flat" => Ok(Timing::Flat),
+            "vip" => Ok(Timing::Vip),
+            _ => Err(UnknownTiming(s.to_string())),
+        }
+    }
+}
+
+/// Approximate machine cycles the COSMAC VIP interpreter takes to run an
+/// instruction, given the value of VX and whether a skip was taken.
+///
+/// The costs are estimates of the interpreter's routines that haven't been
+/// checked against a disassembly of it, so they reproduce the original pacing
+/// only roughly. They don't include the wait for the vertical blank before
+/// drawing, which is modelled by the `display_wait` quirk.
+pub fn vip_cycles(instruction: Instruction, vx: u8, skipped: bool) -> u32 {
+    let execute = match instruction {
+        Instruction::ClearScreen => CLEAR_CYCLES,
+        Instruction::Return | Instruction::Jump(_) | Instruction::Call(_) => 23,
+        Instruction::JumpOffset(_) => 23,
+        Instruction::SkipIfEqualImmediate(..) | Instruction::SkipIfNotEqualImmediate(..) => 12,
+        Instruction::SkipIfEqual(..) | Instruction::SkipIfNotEqual(..) => 16,
+        Instruction::SkipIfKey(_) | Instruction::SkipIfNotKey(_) => 16,
+        Instruction::LoadImmediate(..) => 6,
+        Instruction::AddImmediate(..) => 10,
+        Instruction::Move(..)
+        | Instruction::Or(..)
+        | Instruction::And(..)
+        | Instruction::Xor(..)
+        | Instruction::Add(..)
+        | Instruction::Subtract(..)
+        | Instruction::ShiftRight(..)
+        | Instruction::SubtractReverse(..)
+        | Instruction::ShiftLeft(..) => 44,
+        Instruction::LoadI(_) => 12,
+        Instruction::Random(..) => 36,
+        Instruction::Draw(_, _, rows) => {
+            let shift = (vx % 8) as u32;
+            let row_cycles = match shift {
+                0 => DRAW_ROW_CYCLES,
+                _ => DRAW_ROW_CYCLES + DRAW_UNALIGNED_ROW_CYCLES + shift * DRAW_SHIFT_CYCLES,
+            };
+            DRAW_SETUP_CYCLES + rows as u32 * row_cycles
+        }
+        Instruction::LoadDelay(_) | Instruction::SetDelay(_) | Instruction::SetSound(_) => 10,
+        Instruction::WaitKey(_) => 16,
+        Instruction::AddI(_) => 19,
+        Instruction::LoadFont(_) => 20,
+        Instruction::Bcd(_) => 204,
+        Instruction::Store(x) | Instruction::Load(x) => REGISTER_COPY_CYCLES * (x as u32 + 2),
+        // Not part of the VIP's instruction set, so charged like a simple instruction
+        Instruction::ScrollDown(_)
+        | Instruction::ScrollRight
+        | Instruction::ScrollLeft
+        | Instruction::Exit
+        | Instruction::LowRes
+        | Instruction::HighRes
+        | Instruction::SaveRange(..)
+        | Instruction::LoadRange(..)
+        | Instruction::LongLoadI
+        | Instruction::SelectPlanes(_)
+        | Instruction::LoadAudio
+        | Instruction::LoadBigFont(_)
+        | Instruction::SetPitch(_)
+        | Instruction::StoreFlags(_)
+        | Instruction::LoadFlags(_)
+        | Instruction::Unknown(_) => 12,
+    };
+    let skip = match skipped {
+        true => SKIP_CYCLES,
+        false => 0,
+    };
+    FETCH_CYCLES + execute + skip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_3668_machine_cycles() {
+        assert_eq!(VIP_CYCLES_PER_FRAME, 3668);
+    }
+
+    #[test]
+    fn timing_parses_case_insensitively() {
+        assert_eq!("VIP".parse(), Ok(Timing::Vip));
+        assert_eq!("flat".parse(), Ok(Timing::Flat));
+        assert_eq!(
+            "fast".parse::<Timing>(),
+            Err(UnknownTiming("fast".to_string()))
+        );
+    }
+
+    #[test]
+    fn drawing_costs_more_for_taller_and_unaligned_sprites() {
+        let draw = |rows, x| vip_cycles(Instruction::Draw(0, 1, rows), x, false);
+        assert!(draw(5, 0) < draw(10, 0));
+        assert!(draw(5, 8) < draw(5, 9));
+        assert!(draw(5, 9) < draw(5, 15));
+        assert_eq!(draw(5, 0), draw(5, 16));
+    }
+
+    #[test]
+    fn taken_skips_cost_more() {
+        let skip = Instruction::SkipIfEqualImmediate(0, 0);
+        assert_eq!(
+            vip_cycles(skip, 0, true),
+            vip_cycles(skip, 0, false) + SKIP_CYCLES
+        );
+    }
+}
@@ -0,0 +1,39 @@
+use chip8_rs::assembler::{assemble, ErrorKind};
+use chip8_rs::disassembler::disassemble;
+use chip8_rs::Syntax;
+
+#[test]
+fn disassembly_of_bundled_roms_assembles_to_the_same_bytes() {
+    for entry in std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/roms")).unwrap() {
+        let path = entry.unwrap().path();
+        let rom = std::fs::read(&path).unwrap();
+        let listing = disassemble(&rom, Syntax::Cowgod);
+        let assembled =
+            assemble(&listing).unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+        assert!(assembled == rom, "{} did not round trip", path.display());
+    }
+}
+
+#[test]
+fn assembles_labels_constants_and_sprites() {
+    let source = "
+        X EQU 0x0A
+        start:  LD I, sprite    ; comment
+                LD V0, X + 2
+                DRW V0, V0, 2
+                JP start
+        sprite: DB \"#..##..#\", %01111110
+                DW \"########........\"
+    ";
+    assert_eq!(
+        assemble(source).unwrap(),
+        vec![0xA2, 0x08, 0x60, 0x0C, 0xD0, 0x02, 0x12, 0x00, 0x99, 0x7E, 0xFF, 0x00]
+    );
+}
+
+#[test]
+fn errors_report_their_line() {
+    let err = assemble("CLS\nJP nowhere\n").unwrap_err();
+    assert_eq!(err.line, 2);
+    assert_eq!(err.kind, ErrorKind::UnknownSymbol("nowhere".to_string()));
+}
@@ -0,0 +1,104 @@
+//! Runs the bundled roms headless and compares the screen against the
+//! snapshots in `tests/golden`, or checks the sound they render. Set
+//! `UPDATE_GOLDEN=1` to rewrite the snapshots after an intentional change to
+//! the output.
+
+use chip8_rs::audio::{self, AudioSource};
+use chip8_rs::{headless, Cpu, Quirks};
+use std::path::Path;
+
+const CYCLES_PER_FRAME: u32 = 10;
+const SEED: u64 = 0;
+
+fn check(rom: &str, quirks: Quirks, cycles: u64, golden: &str) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let bytes = std::fs::read(root.join("roms").join(rom)).unwrap();
+    let mut cpu = Cpu::from_bytes(&bytes, quirks).unwrap().with_seed(SEED);
+    headless::run(&mut cpu, cycles, CYCLES_PER_FRAME).unwrap();
+    let screen = headless::screen_text(&cpu);
+
+    let golden = root.join("tests").join("golden").join(golden);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&golden, &screen).unwrap();
+    }
+    let expected = std::fs::read_to_string(&golden).unwrap();
+    assert!(
+        screen == expected,
+        "{} does not match {}:\n{}",
+        rom,
+        golden.display(),
+        screen
+    );
+}
+
+#[test]
+fn ibm_logo() {
+    check("IBM Logo.ch8", Quirks::COSMAC_VIP, 1000, "ibm_logo.txt");
+}
+
+#[test]
+fn test_opcode() {
+    check(
+        "test_opcode.ch8",
+        Quirks::COSMAC_VIP,
+        2000,
+        "test_opcode.txt",
+    );
+}
+
+#[test]
+fn bc_test() {
+    // Expects 8XY6/8XYE to shift VX in place and FX55/FX65 to leave I alone
+    let quirks = Quirks {
+        load_store_increments_i: false,
+        ..Quirks::CHIP_48
+    };
+    check("BC_test.ch8", quirks, 2000, "bc_test.txt");
+}
+
+#[test]
+fn c8_test() {
+    // Expects CHIP-48 shifts and loads, but BNNN to jump relative to V0
+    let quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increments_i: false,
+        ..Quirks::COSMAC_VIP
+    };
+    check("c8_test.c8", quirks, 2000, "c8_test.txt");
+}
+
+#[test]
+fn sctest() {
+    check("SCTEST.CH8", Quirks::SUPER_CHIP, 20000, "sctest.txt");
+}
+
+#[test]
+fn breakout_beeps_into_a_wav() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let bytes =
+        std::fs::read(root.join("roms/Breakout (Brix hack) [David Winter, 1997].ch8")).unwrap();
+    let mut cpu = Cpu::from_bytes(&bytes, Quirks::COSMAC_VIP)
+        .unwrap()
+        .with_seed(SEED);
+    let mut source = AudioSource::new(audio::DEFAULT_SAMPLE_RATE);
+    let samples = headless::render_audio(&mut cpu, 6000, CYCLES_PER_FRAME, &mut source).unwrap();
+    assert_eq!(
+        samples.len(),
+        600 * audio::DEFAULT_SAMPLE_RATE as usize / 60
+    );
+    let beeping = samples.iter().filter(|sample| **sample != 0.).count();
+    assert!(
+        beeping > 0 && beeping < samples.len(),
+        "{} of {}",
+        beeping,
+        samples.len()
+    );
+
+    let path = std::env::temp_dir().join("chip8-rs-breakout.wav");
+    let file = std::fs::File::create(&path).unwrap();
+    audio::write_wav(file, source.sample_rate(), &samples).unwrap();
+    let wav = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(&wav[..4], b"RIFF");
+    assert_eq!(wav.len(), 44 + 2 * samples.len());
+}
@@ -0,0 +1,122 @@
+use chip8_rs::octo::{compile, ErrorKind};
+use chip8_rs::{Cpu, Quirks};
+
+#[test]
+fn compiles_statements_and_forward_references() {
+    let source = "
+        :alias x v1
+        :const SPEED 3
+        : main
+            x := SPEED
+            x += 1
+            i := sprite
+            sprite x x 1
+            if x != 4 then x -= 1
+            draw
+        : draw
+            return
+        : sprite
+            0x80
+    ";
+    assert_eq!(
+        compile(source).unwrap(),
+        vec![
+            0x12, 0x02, // jump main
+            0x61, 0x03, 0x71, 0x01, 0xA2, 0x12, 0xD1, 0x11, //
+            0x31, 0x04, 0x71, 0xFF, 0x22, 0x10, 0x00, 0xEE, 0x80,
+        ]
+    );
+}
+
+#[test]
+fn structured_control_flow_runs() {
+    // Counts v0 up to 10 in a loop, then records which branch of an if ran
+    let source = "
+        :macro bump register { register += 1 }
+        :calc LIMIT { 5 * 2 }
+        : main
+            v0 := 0
+            loop
+                while v0 < LIMIT
+                bump v0
+            again
+            if v0 == 10 begin
+                v1 := 1
+            else
+                v1 := 2
+            end
+            if v0 >= 11 then v2 := 1
+            exit
+    ";
+    let rom = compile(source).unwrap();
+    let mut cpu = Cpu::from_bytes(&rom, Quirks::SUPER_CHIP).unwrap();
+    while !cpu.halted() {
+        cpu.cycle().unwrap();
+    }
+    assert_eq!(&cpu.v()[0..3], &[10, 1, 0]);
+}
+
+#[test]
+fn errors_report_their_line() {
+    let err = compile(": main\n  v0 := 1\n  v0 |= 2\n").unwrap_err();
+    assert_eq!(err.line, 3);
+    assert_eq!(err.kind, ErrorKind::InvalidRegister("2".to_string()));
+
+    let err = compile("v0 := 1").unwrap_err();
+    assert_eq!(err.kind, ErrorKind::MissingMain);
+}
+
+#[test]
+fn negative_bytes_are_twos_complement() {
+    let rom = compile(": main\n:byte -1 :byte -128 :byte { 2 - 3 }").unwrap();
+    assert_eq!(&rom[2..], &[0xFF, 0x80, 0xFF]);
+
+    let err = compile(": main\n:byte -129").unwrap_err();
+    assert_eq!(
+        err.kind,
+        ErrorKind::OutOfRange {
+            value: -129,
+            min: -128,
+            max: 255
+        }
+    );
+}
+
+#[test]
+fn blocks_past_the_address_space_are_errors() {
+    let source = "
+        : main
+        :org 0xFFC
+            if v0 == 1 begin
+                v1 := 1
+            else
+                v1 := 2
+            end
+    ";
+    let err = compile(source).unwrap_err();
+    assert_eq!(err.line, 6);
+    assert_eq!(
+        err.kind,
+        ErrorKind::OutOfRange {
+            value: 0x1004,
+            min: 0,
+            max: 0xFFF
+        }
+    );
+}
+
+#[test]
+fn recursive_macros_are_errors() {
+    let err = compile(":macro m { m }\nm\n").unwrap_err();
+    assert_eq!(err.line, 2);
+    assert_eq!(err.kind, ErrorKind::RecursiveMacro("m".to_string()));
+
+    let source = ":macro a { b }\n:macro b { v0 += 1 a }\n: main\n  a\n";
+    let err = compile(source).unwrap_err();
+    assert_eq!(err.line, 4);
+    assert_eq!(err.kind, ErrorKind::RecursiveMacro("a".to_string()));
+
+    // A macro may use another more than once
+    let source = ":macro b { v0 += 1 }\n:macro a { b b }\n: main\n  a a\n";
+    assert_eq!(&compile(source).unwrap()[2..], &[0x70, 0x01].repeat(4)[..]);
+}